@@ -14,57 +14,116 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use openssl::pkey::{Private, Public};
-use openssl::rand::rand_bytes;
 use openssl::rsa::Rsa;
 use std::collections::VecDeque;
-use std::io::{self, Read, Write};
-use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream};
+use std::env;
+use std::io::ErrorKind;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread::{self, sleep};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use utils::payloads::{
+    AddPeer, Goodbye, GoodbyeReason, InformAddress, InformPublicKey, Payload, RemovePeer, Secret,
+};
+use utils::rpc::{Calls, Request};
+use utils::tls::{self, TlsIdentity, TlsTrust};
+use utils::transport::{Listener, Transport, ACCEPT_HANDSHAKE_TIMEOUT};
 use utils::{
-    decrypt_rsa, encrypt_rsa, get_rsa_public_key, receive_message, send_message, Message,
-    MessageType,
+    get_rsa_public_key, FramedStream, Heartbeat, HeartbeatConfig, Message, MessageType,
+    ProtocolError, RekeyPolicy, SecureChannel, PEER_LINK_MAX_FRAME_LEN, PEER_LINK_MAX_TRANSFER_LEN,
+    SERVER_LINK_MAX_FRAME_LEN,
 };
 mod peers;
 use peers::*;
 
+const DEFAULT_SERVER_ADDRESS: &str = "127.0.0.1:6666";
+// Port 0 lets the OS pick a free port
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:0";
+// Used to check the server's certificate on a TLS link, if present
+const CA_CERTIFICATE: &str = "ca.crt";
+
 // The entrypoint for a thread which constantly waits for info from the main server
 fn listen_to_server(
-    server_socket: Arc<Mutex<TcpStream>>,
-    server_key: [u8; 32],
+    server_socket: Arc<Mutex<SecureChannel<Box<dyn Transport>>>>,
     events: Arc<Mutex<VecDeque<Event>>>,
     all_peers: Arc<Mutex<Vec<Arc<Mutex<Peer>>>>>,
+    heartbeat_config: HeartbeatConfig,
+    rekey_policy: RekeyPolicy,
 ) {
+    let mut heartbeat: Heartbeat = Heartbeat::new(heartbeat_config, Instant::now());
     loop {
         sleep(Duration::from_millis(200));
         {
-            let mut server_socket_guarded: MutexGuard<TcpStream> = server_socket.lock().unwrap();
+            let mut server_socket_guarded: MutexGuard<SecureChannel<Box<dyn Transport>>> =
+                server_socket.lock().unwrap();
             server_socket_guarded
+                .get_ref()
                 .set_read_timeout(Some(Duration::from_millis(500)))
                 .unwrap();
-            let message: Message = match receive_message(&mut server_socket_guarded, &server_key) {
-                Some(value) => value,
-                None => {
+            if heartbeat.timed_out(Instant::now()) {
+                println!("The server stopped responding");
+                let _ = server_socket_guarded.close(GoodbyeReason::TimedOut);
+                return;
+            }
+            if heartbeat.ping_due(Instant::now()) {
+                let ping: Message = heartbeat.ping(Instant::now());
+                if let Err(err) = server_socket_guarded.send(ping) {
+                    println!("Could not ping the server: {}", err);
+                }
+            }
+            let message: Message = match server_socket_guarded.recv() {
+                Ok(value) => value,
+                Err(ProtocolError::Timeout) => continue,
+                Err(ProtocolError::Closed) => {
+                    println!("The server closed the connection");
+                    return;
+                }
+                Err(err @ ProtocolError::Oversized { .. }) => {
+                    println!("Disconnecting from the server: {}", err);
+                    let _ = server_socket_guarded.close(GoodbyeReason::ProtocolViolation);
+                    return;
+                }
+                Err(err) => {
+                    println!("Discarding message from server: {}", err);
                     continue;
                 }
             };
+            heartbeat.received(Instant::now());
             match message.message_type {
+                MessageType::Ping => {
+                    if let Err(err) = server_socket_guarded.send(Heartbeat::pong(&message)) {
+                        println!("Could not answer ping from the server: {}", err);
+                    }
+                }
+                MessageType::Goodbye => {
+                    match Goodbye::from_message(&message) {
+                        Ok(goodbye) => println!("The server has gone: {}", goodbye.reason),
+                        Err(err) => println!("The server sent an invalid goodbye: {}", err),
+                    }
+                    let _ = server_socket_guarded.get_ref().shutdown();
+                    return;
+                }
                 // If there is a new peer,
                 MessageType::AddPeer => {
-                    let message_content: String = String::from_utf8(message.content).unwrap();
-                    println!(
-                        "New peer being added at {}...",
-                        message_content.split_terminator(",").collect::<Vec<&str>>()[0]
-                    );
-                    let new_peer: Peer = Peer::new(
-                        message_content.split_terminator(",").collect::<Vec<&str>>()[0].to_string(),
-                        Rsa::public_key_from_pem(
-                            message_content.split_terminator(",").collect::<Vec<&str>>()[1]
-                                .as_bytes(),
-                        )
-                        .unwrap(),
-                    );
+                    let add_peer: AddPeer = match AddPeer::from_message(&message) {
+                        Ok(value) => value,
+                        Err(err) => {
+                            println!("Server sent an invalid AddPeer message: {}", err);
+                            continue;
+                        }
+                    };
+                    println!("New peer being added at {}...", add_peer.address);
+                    let mut new_peer: Peer = match Peer::new(add_peer.address, add_peer.public_key)
+                    {
+                        Ok(value) => value,
+                        Err(err) => {
+                            println!("Could not connect to new peer: {}", err);
+                            continue;
+                        }
+                    };
+                    new_peer.channel.set_rekey_policy(rekey_policy);
                     let mutex_peer: Arc<Mutex<Peer>> = Arc::new(Mutex::new(new_peer));
                     {
                         all_peers.lock().unwrap().push(mutex_peer.clone());
@@ -78,22 +137,26 @@ fn listen_to_server(
                 MessageType::RemovePeer => {
                     let peers_guarded: MutexGuard<Vec<Arc<Mutex<Peer>>>> =
                         all_peers.lock().unwrap();
-                    let address: String = String::from_utf8(message.content).unwrap();
-                    events.lock().unwrap().push_back(Event::PeerRemoved(
-                        peers_guarded
-                            .clone()
-                            .into_iter()
-                            .find(|x| {
-                                x.lock()
-                                    .unwrap()
-                                    .tcp_stream
-                                    .peer_addr()
-                                    .unwrap()
-                                    .to_string()
-                                    == address
-                            })
-                            .unwrap(),
-                    ));
+                    let remove_peer: RemovePeer = match RemovePeer::from_message(&message) {
+                        Ok(value) => value,
+                        Err(err) => {
+                            println!("Server sent an invalid RemovePeer message: {}", err);
+                            continue;
+                        }
+                    };
+                    println!("Peer at {} has left", remove_peer.address);
+                    let removed_key: Vec<u8> = remove_peer.public_key.public_key_to_der().unwrap();
+                    let removed_peer: Option<Arc<Mutex<Peer>>> =
+                        peers_guarded.clone().into_iter().find(|x| {
+                            x.lock().unwrap().public_key.public_key_to_der().unwrap() == removed_key
+                        });
+                    if let Some(peer) = removed_peer {
+                        events.lock().unwrap().push_back(Event::PeerRemoved(peer));
+                    }
+                }
+                // Such as news of a match
+                MessageType::DEBUG => {
+                    println!("{:?}", String::from_utf8(message.content));
                 }
                 _ => {}
             }
@@ -102,74 +165,40 @@ fn listen_to_server(
 }
 
 // The entrypoint for the thread which constantly listens for new peers to connect
-fn listen_for_peers(
-    port: String,
+fn listen_for_peers<L: Listener>(
+    listener: L,
     all_peers: Arc<Mutex<Vec<Arc<Mutex<Peer>>>>>,
     events: Arc<Mutex<VecDeque<Event>>>,
-    server_socket: Arc<Mutex<TcpStream>>,
+    server_socket: Arc<Mutex<SecureChannel<Box<dyn Transport>>>>,
     key: Rsa<Private>,
-    aes_key: [u8; 32],
+    rekey_policy: RekeyPolicy,
 ) {
-    let listener: TcpListener = TcpListener::bind("127.0.0.1:".to_owned() + &port).unwrap();
-    println!(
-        "Now listening on port {}...",
-        listener.local_addr().unwrap()
-    );
+    let address: String = listener.local_address().unwrap();
+    println!("Now listening on {}...", address);
     // Inform the server of the listener's address
-    let message: Message = Message::new(
-        listener
-            .local_addr()
-            .unwrap()
-            .to_string()
-            .as_bytes()
-            .to_vec(),
-        MessageType::InformAddress,
-    );
-    let mut tag: [u8; 16] = [0; 16];
-    rand_bytes(&mut tag).unwrap();
-    send_message(
-        message,
-        &mut server_socket.lock().unwrap(),
-        &aes_key,
-        &mut tag,
-    )
-    .unwrap();
+    let message: Message = InformAddress { address }.to_message();
+    server_socket
+        .lock()
+        .unwrap()
+        .send_reliable(message)
+        .unwrap();
     loop {
+        let new_stream: Box<dyn Transport> = match listener.accept() {
+            Ok(value) => value,
+            // Nothing more can ever arrive
+            Err(err) if err.kind() == ErrorKind::NotConnected => return,
+            Err(_) => continue,
+        };
         {
-            // When a new peer connects,
-            let mut new_stream: TcpStream = listener.accept().unwrap().0;
-            // Handshake with them
-            let mut aes_key: [u8; 32] = [0; 32];
-            let mut received_rsa_data: [u8; 256] = [0; 256];
-            {
-                new_stream.read(&mut received_rsa_data).unwrap();
-            }
-            let decrypted_data = decrypt_rsa(&received_rsa_data, &key);
-            for i in 0..32 {
-                aes_key[i] = decrypted_data[i];
-            }
-            println!("Connecting to new peer...");
-            // And get their public key
-            let message: Message = Message::new(
-                new_stream
-                    .peer_addr()
-                    .unwrap()
-                    .to_string()
-                    .as_bytes()
-                    .to_vec(),
-                MessageType::RequestPublicKey,
-            );
-            println!("Connected to new peer");
-            let mut tag: [u8; 16] = [0; 16];
-            rand_bytes(&mut tag).unwrap();
-            send_message(message, &mut new_stream, &aes_key, &mut tag).unwrap();
-            let received_message: Message = receive_message(&mut new_stream, &aes_key).unwrap();
-            let new_peer: Peer = Peer {
-                tcp_stream: new_stream,
-                aes_key,
-                public_key: Rsa::public_key_from_pem(received_message.content.as_slice()).unwrap(),
-                tag,
+            // When a new peer connects, handshake with them
+            let mut new_peer: Peer = match accept_peer(new_stream, &key) {
+                Ok(value) => value,
+                Err(err) => {
+                    println!("Handshake with new peer failed: {}", err);
+                    continue;
+                }
             };
+            new_peer.channel.set_rekey_policy(rekey_policy);
             println!("New peer obtained from server");
             let mutex_peer: Arc<Mutex<Peer>> = Arc::new(Mutex::new(new_peer));
             {
@@ -185,42 +214,145 @@ fn listen_for_peers(
     }
 }
 
+// Completes the handshake with a peer which has just connected to our listener
+fn accept_peer(transport: Box<dyn Transport>, key: &Rsa<Private>) -> Result<Peer, ProtocolError> {
+    let new_stream: FramedStream<Box<dyn Transport>> =
+        FramedStream::with_max_frame_len(transport, PEER_LINK_MAX_FRAME_LEN);
+    let mut channel: SecureChannel<Box<dyn Transport>> =
+        SecureChannel::respond_within(new_stream, key, ACCEPT_HANDSHAKE_TIMEOUT)?;
+    channel.set_max_transfer_len(PEER_LINK_MAX_TRANSFER_LEN);
+    println!("Connecting to new peer...");
+    // And get their public key, keeping anything else they send for their message handling thread
+    let calls: Arc<Calls> = Arc::new(Calls::default());
+    let message: Message = Message::new(Vec::new(), MessageType::RequestPublicKey);
+    println!("Connected to new peer");
+    channel
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_millis(200)))?;
+    let mut unhandled: VecDeque<Message> = VecDeque::new();
+    let reply: Message = calls
+        .call(&mut channel, message)?
+        .await_reply_on(&mut channel, |other| unhandled.push_back(other))?;
+    let public_key: Rsa<Public> = InformPublicKey::from_message(&reply)?.public_key;
+    Ok(Peer {
+        channel,
+        public_key,
+        calls,
+        unhandled,
+    })
+}
+
 // The entrypoint for the thread which constantly handles messages from a peer
-fn handle_peer_messages(peer: Arc<Mutex<Peer>>, public_key: Arc<Rsa<Public>>) {
+fn handle_peer_messages(
+    peer: Arc<Mutex<Peer>>,
+    public_key: Arc<Rsa<Public>>,
+    events: Arc<Mutex<VecDeque<Event>>>,
+    heartbeat_config: HeartbeatConfig,
+) {
+    let mut heartbeat: Heartbeat = Heartbeat::new(heartbeat_config, Instant::now());
     loop {
         sleep(Duration::from_millis(200));
         {
-            let message: Message = match peer.lock().unwrap().get_message() {
-                Some(value) => value,
-                None => continue,
+            let mut peer_guarded: MutexGuard<Peer> = peer.lock().unwrap();
+            // Don't hold on to the peer while waiting, others need it to find and remove peers
+            peer_guarded
+                .channel
+                .get_ref()
+                .set_read_timeout(Some(Duration::from_millis(200)))
+                .unwrap();
+            if heartbeat.timed_out(Instant::now()) {
+                println!("Peer stopped responding");
+                let _ = peer_guarded.channel.close(GoodbyeReason::TimedOut);
+                break;
+            }
+            if heartbeat.ping_due(Instant::now()) {
+                let ping: Message = heartbeat.ping(Instant::now());
+                if let Err(err) = peer_guarded.channel.send(ping) {
+                    println!("Could not ping peer: {}", err);
+                }
+            }
+            let received: Result<Message, ProtocolError> = match peer_guarded.unhandled.pop_front()
+            {
+                Some(message) => Ok(message),
+                None => peer_guarded.channel.recv(),
+            };
+            let message: Message = match received {
+                Ok(value) => value,
+                Err(ProtocolError::Closed) => break,
+                Err(err @ ProtocolError::Oversized { .. }) => {
+                    println!("Disconnecting from peer: {}", err);
+                    let _ = peer_guarded.channel.close(GoodbyeReason::ProtocolViolation);
+                    break;
+                }
+                Err(_) => continue,
+            };
+            heartbeat.received(Instant::now());
+            // Replies go to whoever made the call
+            let message: Message = match peer_guarded.calls.dispatch(message) {
+                Ok(Some(value)) => value,
+                Ok(None) => continue,
+                Err(err) => {
+                    println!("Discarding invalid reply from peer: {}", err);
+                    continue;
+                }
             };
-            // If they want our public key,
             match message.message_type {
-                MessageType::RequestPublicKey => {
-                    // Send it to them
-                    let message: Message =
-                        Message::new(public_key.public_key_to_pem().unwrap(), MessageType::NORMAL);
-                    peer.lock().unwrap().send_message(message);
+                MessageType::Request => {
+                    let request: Request = match Request::from_message(&message) {
+                        Ok(value) => value,
+                        Err(err) => {
+                            println!("Peer sent an invalid request: {}", err);
+                            continue;
+                        }
+                    };
+                    // If they want our public key, send it to them
+                    if let MessageType::RequestPublicKey = request.message.message_type {
+                        let message: Message = request.reply(
+                            InformPublicKey {
+                                public_key: public_key.as_ref().clone(),
+                            }
+                            .to_message(),
+                        );
+                        if let Err(err) = peer_guarded.channel.send(message) {
+                            println!("Could not send public key to peer: {}", err);
+                        }
+                    }
+                }
+                MessageType::Ping => {
+                    if let Err(err) = peer_guarded.channel.send(Heartbeat::pong(&message)) {
+                        println!("Could not answer ping from peer: {}", err);
+                    }
+                }
+                MessageType::Goodbye => {
+                    match Goodbye::from_message(&message) {
+                        Ok(goodbye) => println!("Peer has gone: {}", goodbye.reason),
+                        Err(err) => println!("Peer sent an invalid goodbye: {}", err),
+                    }
+                    let _ = peer_guarded.channel.get_ref().shutdown();
+                    break;
                 }
                 _ => {}
             }
         }
     }
+    // The peer is let go of by now, handling the event needs it too
+    events.lock().unwrap().push_back(Event::PeerRemoved(peer));
 }
 
 // The entrypoint for the thread which constantly handles events
 fn handle_events(
     events: Arc<Mutex<VecDeque<Event>>>,
-    server_socket: Arc<Mutex<TcpStream>>,
+    all_peers: Arc<Mutex<Vec<Arc<Mutex<Peer>>>>>,
+    server_socket: Arc<Mutex<SecureChannel<Box<dyn Transport>>>>,
     public_key: Arc<Rsa<Public>>,
     user_crush: String,
     crush_user: String,
-    server_key: [u8; 32],
+    heartbeat_config: HeartbeatConfig,
 ) {
     loop {
         {
             let mut event_guard: MutexGuard<VecDeque<Event>> = events.lock().unwrap();
-            if event_guard.len() > 0 {
+            if !event_guard.is_empty() {
                 let event: &Event = event_guard.front().unwrap();
                 match event {
                     // If a new peer has been added,
@@ -228,37 +360,48 @@ fn handle_events(
                         {
                             let cloned_peer = peer.clone();
                             let cloned_public_key = public_key.clone();
+                            let cloned_events = events.clone();
                             thread::spawn(move || {
                                 // Spawn a new thread to handle any traffic from them
-                                handle_peer_messages(cloned_peer, cloned_public_key);
+                                handle_peer_messages(
+                                    cloned_peer,
+                                    cloned_public_key,
+                                    cloned_events,
+                                    heartbeat_config,
+                                );
                             });
                         }
-                        // And send the server the shared secret with them
-                        let aes_key = peer.lock().unwrap().aes_key;
-                        let secret1: Vec<u8> =
-                            format!("{}{:x?}", user_crush, aes_key).as_bytes().to_vec();
-                        let secret2: Vec<u8> =
-                            format!("{}{:x?}", crush_user, aes_key).as_bytes().to_vec();
-                        let mut tag: [u8; 16] = [0; 16];
-                        rand_bytes(&mut tag).unwrap();
+                        // And send the server the shared secret with them, which is kept apart
+                        // from the keys sealing the connection
+                        let pair_secret: [u8; 32] =
+                            peer.lock().unwrap().channel.session().pair_secret;
+                        let secret1: Vec<u8> = format!("{}{:x?}", user_crush, pair_secret)
+                            .as_bytes()
+                            .to_vec();
+                        let secret2: Vec<u8> = format!("{}{:x?}", crush_user, pair_secret)
+                            .as_bytes()
+                            .to_vec();
                         println!("Sending...");
-                        send_message(
-                            Message::new(secret1, MessageType::Secret),
-                            &mut server_socket.lock().unwrap(),
-                            &server_key,
-                            &mut tag,
-                        )
-                        .unwrap();
-                        send_message(
-                            Message::new(secret2, MessageType::Secret),
-                            &mut server_socket.lock().unwrap(),
-                            &server_key,
-                            &mut tag,
-                        )
-                        .unwrap();
-                        println!("Secret sent");
+                        let mut server_socket_guarded: MutexGuard<
+                            SecureChannel<Box<dyn Transport>>,
+                        > = server_socket.lock().unwrap();
+                        // Sent again by the connection until the server acknowledges them
+                        let result: Result<u64, ProtocolError> = server_socket_guarded
+                            .send_reliable(Secret { secret: secret1 }.to_message())
+                            .and_then(|_| {
+                                server_socket_guarded
+                                    .send_reliable(Secret { secret: secret2 }.to_message())
+                            });
+                        match result {
+                            Ok(_) => println!("Secret sent"),
+                            Err(err) => println!("Could not send secret: {}", err),
+                        }
+                    }
+                    Event::PeerRemoved(peer) => {
+                        // Close the connection, which ends the peer's message handling thread
+                        let _ = peer.lock().unwrap().channel.get_ref().shutdown();
+                        all_peers.lock().unwrap().retain(|x| !Arc::ptr_eq(x, peer));
                     }
-                    Event::PeerRemoved(_) => {}
                 }
             }
             event_guard.pop_front();
@@ -267,7 +410,11 @@ fn handle_events(
     }
 }
 
-fn main() -> std::io::Result<()> {
+// Usage: client [server address] [peer listener address]
+// Either address can be TCP (e.g. 127.0.0.1:6666) or a Unix socket (e.g. unix:/run/crush.sock),
+// with ws: in front to run over WebSockets and/or tls: in front to run over TLS.
+// Heartbeats follow CRUSH_HEARTBEAT_INTERVAL_MS and CRUSH_HEARTBEAT_TIMEOUT_MS if they are set.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut user_name: String = String::new();
     let mut crush_name: String = String::new();
     let stdin = io::stdin();
@@ -279,7 +426,7 @@ fn main() -> std::io::Result<()> {
     stdin.read_line(&mut crush_name)?;
     let user_crush: String = user_name.clone() + &crush_name;
     let crush_user: String = crush_name + &user_name;
-    let server_public_key: Rsa<Public> = get_rsa_public_key("server.pub");
+    let server_public_key: Rsa<Public> = get_rsa_public_key("server.pub")?;
     let private_key: Rsa<Private> = Rsa::generate(2048).unwrap();
     let public_key: Arc<Rsa<Public>> = Arc::new(
         Rsa::from_public_components(
@@ -288,28 +435,53 @@ fn main() -> std::io::Result<()> {
         )
         .unwrap(),
     );
-    let mut aes_key: [u8; 32] = [0; 32];
-    let mut tag: [u8; 16] = [0; 16];
-    let socket: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0);
+    let mut args: env::Args = env::args();
+    let server_address: String = args.nth(1).unwrap_or(DEFAULT_SERVER_ADDRESS.to_string());
+    let listen_address: String = args.next().unwrap_or(DEFAULT_LISTEN_ADDRESS.to_string());
+    let listener: Box<dyn Listener> =
+        tls::bind_to(&listen_address, || TlsIdentity::self_signed(&private_key))?;
+    let heartbeat_config: HeartbeatConfig = HeartbeatConfig::from_env();
+    let rekey_policy: RekeyPolicy = RekeyPolicy::from_env();
     let events: Arc<Mutex<VecDeque<Event>>> = Arc::new(Mutex::new(VecDeque::new()));
     let all_peers: Arc<Mutex<Vec<Arc<Mutex<Peer>>>>> = Arc::new(Mutex::new(Vec::new()));
-    rand_bytes(&mut aes_key)?;
-    rand_bytes(&mut tag)?;
-    let server_connection: Arc<Mutex<TcpStream>> =
-        Arc::new(Mutex::new(TcpStream::connect("127.0.0.1:6666")?));
-    let encrypted_aes_key: Vec<u8> = encrypt_rsa(&aes_key, &server_public_key);
-    server_connection
-        .lock()
-        .unwrap()
-        .write(&encrypted_aes_key)
-        .unwrap();
+    // Trust the local CA if there is one, otherwise only the server's own key
+    let server_trust: TlsTrust = if Path::new(CA_CERTIFICATE).exists() {
+        TlsTrust::Authorities(tls::load_certificates(CA_CERTIFICATE)?)
+    } else {
+        TlsTrust::PinnedKey(server_public_key.clone())
+    };
+    let server_transport: Box<dyn Transport> = tls::connect_to(&server_address, &server_trust)?;
+    let server_stream: FramedStream<Box<dyn Transport>> =
+        FramedStream::with_max_frame_len(server_transport, SERVER_LINK_MAX_FRAME_LEN);
+    let mut server_channel: SecureChannel<Box<dyn Transport>> =
+        match SecureChannel::initiate(server_stream, &server_public_key) {
+            Ok(value) => value,
+            Err(ProtocolError::KeyNotConfirmed) => {
+                println!(
+                    "The server could not prove it holds the key in server.pub, so either \
+                     server.pub is out of date or this is not the real server"
+                );
+                return Err(ProtocolError::KeyNotConfirmed.into());
+            }
+            Err(err) => return Err(err.into()),
+        };
+    server_channel.set_rekey_policy(rekey_policy);
+    // Shared by every thread which talks to the server, so they all count from the same sequence
+    // numbers
+    let server_connection: Arc<Mutex<SecureChannel<Box<dyn Transport>>>> =
+        Arc::new(Mutex::new(server_channel));
     {
-        let cloned_key = aes_key.clone();
         let cloned_socket = server_connection.clone();
         let cloned_events = events.clone();
         let cloned_peers = all_peers.clone();
         thread::spawn(move || {
-            listen_to_server(cloned_socket, cloned_key, cloned_events, cloned_peers)
+            listen_to_server(
+                cloned_socket,
+                cloned_events,
+                cloned_peers,
+                heartbeat_config,
+                rekey_policy,
+            )
         });
     }
     {
@@ -318,44 +490,138 @@ fn main() -> std::io::Result<()> {
         let cloned_socket = server_connection.clone();
         thread::spawn(move || {
             listen_for_peers(
-                socket.port().to_string(),
+                listener,
                 cloned_peers,
                 cloned_events,
                 cloned_socket,
                 private_key,
-                aes_key.clone(),
+                rekey_policy,
             );
         });
     }
     {
         let cloned_events = events.clone();
+        let cloned_peers = all_peers.clone();
         let cloned_socket = server_connection.clone();
         let cloned_key = public_key.clone();
         thread::spawn(move || {
             handle_events(
                 cloned_events,
+                cloned_peers,
                 cloned_socket,
                 cloned_key,
                 user_crush,
                 crush_user,
-                aes_key,
+                heartbeat_config,
             );
         });
     }
-    let message: Message = Message::new(
-        public_key.public_key_to_pem().unwrap(),
-        MessageType::InformPublicKey,
-    );
+    let message: Message = InformPublicKey {
+        public_key: public_key.as_ref().clone(),
+    }
+    .to_message();
     println!("Sending RSA key...");
-    send_message(
-        message,
-        &mut server_connection.lock().unwrap(),
-        &mut aes_key,
-        &mut tag,
-    )
-    .unwrap();
+    server_connection.lock().unwrap().send_reliable(message)?;
     println!("Sent RSA key!");
-    loop {
-        sleep(Duration::from_secs(20));
+    // Run until interrupted or terminated, then say goodbye to the peers and the server
+    let (shutdown_sender, shutdown_receiver) = mpsc::channel::<()>();
+    ctrlc::set_handler(move || {
+        let _ = shutdown_sender.send(());
+    })?;
+    let _ = shutdown_receiver.recv();
+    println!("Leaving...");
+    let peers: Vec<Arc<Mutex<Peer>>> = all_peers.lock().unwrap().clone();
+    for peer in peers {
+        if let Err(err) = peer.lock().unwrap().channel.close(GoodbyeReason::Leaving) {
+            println!("Could not say goodbye to peer: {}", err);
+        }
+    }
+    server_connection
+        .lock()
+        .unwrap()
+        .close(GoodbyeReason::Leaving)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::{MemoryListener, MemoryPipe};
+
+    fn public_key(key: &Rsa<Private>) -> Rsa<Public> {
+        Rsa::public_key_from_pem(&key.public_key_to_pem().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn peers_connecting_to_the_listener_are_added() {
+        let server_key: Rsa<Private> = Rsa::generate(2048).unwrap();
+        let our_key: Rsa<Private> = Rsa::generate(2048).unwrap();
+        let their_key: Rsa<Private> = Rsa::generate(2048).unwrap();
+        // The server's end of our connection to it
+        let (ours, theirs) = MemoryPipe::pair();
+        let cloned_server_key: Rsa<Private> = server_key.clone();
+        let server = thread::spawn(move || {
+            SecureChannel::respond(FramedStream::new(theirs), &cloned_server_key).unwrap()
+        });
+        let transport: Box<dyn Transport> = Box::new(ours);
+        let server_channel: SecureChannel<Box<dyn Transport>> =
+            SecureChannel::initiate(FramedStream::new(transport), &public_key(&server_key))
+                .unwrap();
+        let mut server: SecureChannel<MemoryPipe> = server.join().unwrap();
+        let server_socket: Arc<Mutex<SecureChannel<Box<dyn Transport>>>> =
+            Arc::new(Mutex::new(server_channel));
+        let all_peers: Arc<Mutex<Vec<Arc<Mutex<Peer>>>>> = Arc::new(Mutex::new(Vec::new()));
+        let events: Arc<Mutex<VecDeque<Event>>> = Arc::new(Mutex::new(VecDeque::new()));
+        let (listener, connector) = MemoryListener::new("memory:peers");
+        let listening = {
+            let cloned_peers = all_peers.clone();
+            let cloned_events = events.clone();
+            let cloned_socket = server_socket.clone();
+            let cloned_key = our_key.clone();
+            thread::spawn(move || {
+                listen_for_peers(
+                    listener,
+                    cloned_peers,
+                    cloned_events,
+                    cloned_socket,
+                    cloned_key,
+                    RekeyPolicy::never(),
+                )
+            })
+        };
+        // The server is told where to send peers
+        let inform: InformAddress = InformAddress::from_message(&server.recv().unwrap()).unwrap();
+        assert_eq!(inform.address, "memory:peers");
+        // A peer connects, and is asked for its public key
+        let mut peer: SecureChannel<MemoryPipe> = SecureChannel::initiate(
+            FramedStream::with_max_frame_len(connector.connect().unwrap(), PEER_LINK_MAX_FRAME_LEN),
+            &public_key(&our_key),
+        )
+        .unwrap();
+        let request: Request = Request::from_message(&peer.recv().unwrap()).unwrap();
+        assert_eq!(request.message.message_type, MessageType::RequestPublicKey);
+        let inform: Message = InformPublicKey {
+            public_key: public_key(&their_key),
+        }
+        .to_message();
+        peer.send(request.reply(inform)).unwrap();
+        // Once nothing more can connect, the listener's thread ends
+        drop(connector);
+        listening.join().unwrap();
+        let all_peers: MutexGuard<Vec<Arc<Mutex<Peer>>>> = all_peers.lock().unwrap();
+        assert_eq!(all_peers.len(), 1);
+        assert_eq!(
+            all_peers[0]
+                .lock()
+                .unwrap()
+                .public_key
+                .public_key_to_der()
+                .unwrap(),
+            public_key(&their_key).public_key_to_der().unwrap()
+        );
+        assert!(matches!(
+            events.lock().unwrap().front(),
+            Some(Event::PeerAdded(_))
+        ));
     }
 }
@@ -14,12 +14,16 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use openssl::pkey::Public;
-use openssl::rand::rand_bytes;
 use openssl::rsa::Rsa;
-use std::io::Write;
-use std::net::TcpStream;
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
-use utils::{encrypt_rsa, receive_message, send_message, Message};
+use utils::rpc::Calls;
+use utils::tls::{self, TlsTrust};
+use utils::transport::Transport;
+use utils::{
+    FramedStream, Message, ProtocolError, SecureChannel, PEER_LINK_MAX_FRAME_LEN,
+    PEER_LINK_MAX_TRANSFER_LEN,
+};
 
 pub enum Event {
     PeerAdded(Arc<Mutex<Peer>>),
@@ -27,35 +31,30 @@ pub enum Event {
 }
 
 pub struct Peer {
-    pub tcp_stream: TcpStream,
-    pub aes_key: [u8; 32],
+    pub channel: SecureChannel<Box<dyn Transport>>,
     pub public_key: Rsa<Public>,
-    pub tag: [u8; 16],
+    // The calls made to the peer, whose replies are picked out by its message handling thread
+    pub calls: Arc<Calls>,
+    // Received before the message handling thread started, to be handled by it first
+    pub unhandled: VecDeque<Message>,
 }
 
 impl Peer {
-    pub fn new(address: String, public_key: Rsa<Public>) -> Self {
-        let mut tcp_stream: TcpStream = TcpStream::connect(address).unwrap();
-        let mut aes_key: [u8; 32] = [0; 32];
-        rand_bytes(&mut aes_key).unwrap();
-        let mut tag: [u8; 16] = [0; 16];
-        rand_bytes(&mut tag).unwrap();
-        tcp_stream
-            .write(&encrypt_rsa(&aes_key, &public_key))
-            .unwrap();
-        Peer {
-            tcp_stream,
-            aes_key,
+    // The address can be TCP or a Unix socket, with or without TLS, as the peer told the server.
+    // A peer's certificate is self-signed, so it is pinned to the key the server gave us.
+    pub fn new(address: String, public_key: Rsa<Public>) -> Result<Self, ProtocolError> {
+        let transport: Box<dyn Transport> =
+            tls::connect_to(&address, &TlsTrust::PinnedKey(public_key.clone()))?;
+        let stream: FramedStream<Box<dyn Transport>> =
+            FramedStream::with_max_frame_len(transport, PEER_LINK_MAX_FRAME_LEN);
+        let mut channel: SecureChannel<Box<dyn Transport>> =
+            SecureChannel::initiate(stream, &public_key)?;
+        channel.set_max_transfer_len(PEER_LINK_MAX_TRANSFER_LEN);
+        Ok(Peer {
+            channel,
             public_key,
-            tag,
-        }
-    }
-
-    pub fn get_message(&mut self) -> Option<Message> {
-        receive_message(&mut self.tcp_stream, &self.aes_key)
-    }
-
-    pub fn send_message(&mut self, message: Message) {
-        send_message(message, &mut self.tcp_stream, &self.aes_key, &mut self.tag).unwrap();
+            calls: Arc::new(Calls::default()),
+            unhandled: VecDeque::new(),
+        })
     }
 }
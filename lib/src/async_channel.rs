@@ -0,0 +1,310 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The async version of SecureChannel, for running connections on a tokio reactor rather than a
+// thread each. Only enabled with the async feature.
+// The handshake and records are the same as the blocking channel's (see handshake.rs and
+// record.rs), so either end can be blocking or async.
+
+use crate::framing::{encode_frame, FrameReader, READ_CHUNK_LEN};
+use crate::handshake::{Hello, Initiator, Responder, Session};
+use crate::padding::PaddingPolicy;
+use crate::payloads::{Goodbye, GoodbyeReason, Payload};
+use crate::record::{RecordLayer, RekeyPolicy, TRANSFER_PIECES_PER_TURN};
+use crate::transfer::Progress;
+use crate::{Message, ProtocolError, SERVER_LINK_MAX_FRAME_LEN};
+use openssl::pkey::{Private, Public};
+use openssl::rsa::Rsa;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Frames over an async stream, in the same format as FramedStream
+#[derive(Debug)]
+struct AsyncFramedStream<S> {
+    stream: S,
+    reader: FrameReader,
+    max_frame_len: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncFramedStream<S> {
+    fn new(stream: S) -> Self {
+        AsyncFramedStream {
+            stream,
+            reader: FrameReader::default(),
+            max_frame_len: SERVER_LINK_MAX_FRAME_LEN,
+        }
+    }
+
+    async fn write_frame(&mut self, payload: &[u8]) -> Result<(), ProtocolError> {
+        if self.reader.is_closed() {
+            return Err(ProtocolError::Closed);
+        }
+        let frame: Vec<u8> = encode_frame(payload, self.max_frame_len)?;
+        self.stream.write_all(&frame).await?;
+        Ok(self.stream.flush().await?)
+    }
+
+    // Anything read is kept until the frame is complete, so this can be cancelled while waiting
+    async fn read_frame(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        loop {
+            if let Some(frame) = self.reader.next_frame(self.max_frame_len)? {
+                return Ok(frame);
+            }
+            let mut chunk: [u8; READ_CHUNK_LEN] = [0; READ_CHUNK_LEN];
+            let n: usize = self.stream.read(&mut chunk).await?;
+            self.reader.received(&chunk[..n])?;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AsyncSecureChannel<S> {
+    stream: AsyncFramedStream<S>,
+    record: RecordLayer,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncSecureChannel<S> {
+    // Wraps a stream which has already completed the handshake
+    pub fn new(stream: S, session: Session) -> Self {
+        AsyncSecureChannel {
+            stream: AsyncFramedStream::new(stream),
+            record: RecordLayer::new(session),
+        }
+    }
+
+    // Runs the handshake from the connecting side, with the public key of whoever we are connecting to
+    pub async fn initiate(stream: S, their_key: &Rsa<Public>) -> Result<Self, ProtocolError> {
+        let mut stream: AsyncFramedStream<S> = AsyncFramedStream::new(stream);
+        let hello: Vec<u8> = Hello::ours().as_bytes();
+        stream.write_frame(&hello).await?;
+        let reply_bytes: Vec<u8> = stream.read_frame().await?;
+        let (initiator, key_share) = Initiator::new(&hello, &reply_bytes, their_key)?;
+        stream.write_frame(&key_share).await?;
+        let session: Session = initiator.finish(&stream.read_frame().await?, their_key)?;
+        Ok(AsyncSecureChannel {
+            stream,
+            record: RecordLayer::new(session),
+        })
+    }
+
+    // Runs the handshake from the listening side, with our own private key
+    pub async fn respond(stream: S, our_key: &Rsa<Private>) -> Result<Self, ProtocolError> {
+        let mut stream: AsyncFramedStream<S> = AsyncFramedStream::new(stream);
+        let (responder, reply) = Responder::new(&stream.read_frame().await?)?;
+        stream.write_frame(&reply).await?;
+        responder.accepted()?;
+        let (session, key_share) = responder.finish(&stream.read_frame().await?, our_key)?;
+        stream.write_frame(&key_share).await?;
+        Ok(AsyncSecureChannel {
+            stream,
+            record: RecordLayer::new(session),
+        })
+    }
+
+    // As with SecureChannel::send, a message too large for a frame goes out over later calls
+    pub async fn send(&mut self, message: Message) -> Result<(), ProtocolError> {
+        self.send_with_id(&message, 0).await
+    }
+
+    // Sends a message which is sent again until the other end acknowledges it, returning its ID
+    pub async fn send_reliable(&mut self, message: Message) -> Result<u64, ProtocolError> {
+        let id: u64 = self.record.track(&message);
+        self.send_with_id(&message, id).await?;
+        Ok(id)
+    }
+
+    pub fn is_acknowledged(&self, id: u64) -> bool {
+        self.record.is_acknowledged(id)
+    }
+
+    pub fn outgoing_transfers(&self) -> Vec<Progress> {
+        self.record.outgoing_transfers()
+    }
+
+    pub fn incoming_transfers(&self) -> Vec<Progress> {
+        self.record.incoming_transfers()
+    }
+
+    pub fn set_max_transfer_len(&mut self, max_transfer_len: usize) {
+        self.record.set_max_transfer_len(max_transfer_len);
+    }
+
+    async fn send_with_id(
+        &mut self,
+        message: &Message,
+        message_id: u64,
+    ) -> Result<(), ProtocolError> {
+        self.send_owed_acks().await?;
+        if !self.record.fits(message, self.stream.max_frame_len) {
+            self.record.queue_transfer(message.clone(), message_id);
+            return self.send_transfer_pieces().await;
+        }
+        // Pieces already queued go first, so nothing follows a Goodbye
+        self.send_transfer_pieces().await?;
+        self.write_record(message, message_id).await
+    }
+
+    async fn send_transfer_pieces(&mut self) -> Result<(), ProtocolError> {
+        for _ in 0..TRANSFER_PIECES_PER_TURN {
+            let Some(piece) = self.record.transfer_piece() else {
+                break;
+            };
+            self.write_record(&piece, 0).await?;
+            self.record.transfer_piece_sent();
+        }
+        Ok(())
+    }
+
+    async fn send_owed_acks(&mut self) -> Result<(), ProtocolError> {
+        while let Some(ack) = self.record.owed_ack() {
+            self.write_record(&ack, 0).await?;
+            self.record.ack_sent();
+        }
+        Ok(())
+    }
+
+    async fn write_record(
+        &mut self,
+        message: &Message,
+        message_id: u64,
+    ) -> Result<(), ProtocolError> {
+        if self.record.rekey_due() {
+            let key_update: Vec<u8> = self.record.key_update(self.stream.max_frame_len)?;
+            self.stream.write_frame(&key_update).await?;
+            self.record.rekeyed()?;
+        }
+        let frame: Vec<u8> = self
+            .record
+            .seal(message, message_id, self.stream.max_frame_len)?;
+        self.stream.write_frame(&frame).await?;
+        self.record.sent(frame.len())
+    }
+
+    // As with SecureChannel::recv, Acks and repeats are dealt with here and retransmission is
+    // driven by receiving. Safe to cancel while waiting for a frame, but not once one has arrived
+    // and its Ack is being sent.
+    pub async fn recv(&mut self) -> Result<Message, ProtocolError> {
+        self.send_owed_acks().await?;
+        for (id, message) in self.record.overdue() {
+            self.send_with_id(&message, id).await?;
+        }
+        self.send_transfer_pieces().await?;
+        loop {
+            let frame: Vec<u8> = self.stream.read_frame().await?;
+            let message: Option<Message> = self.record.open(&frame, self.stream.max_frame_len)?;
+            let acknowledged: Result<(), ProtocolError> = self.send_owed_acks().await;
+            match message {
+                Some(message) => return Ok(message),
+                None => acknowledged?,
+            }
+        }
+    }
+
+    // Tells the other end why we are going, then shuts down our side of the stream either way
+    pub async fn close(&mut self, reason: GoodbyeReason) -> Result<(), ProtocolError> {
+        let sent: Result<(), ProtocolError> = self.send(Goodbye { reason }.to_message()).await;
+        self.stream.stream.shutdown().await?;
+        sent
+    }
+
+    pub fn session(&self) -> &Session {
+        self.record.session()
+    }
+
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.stream.max_frame_len = max_frame_len;
+    }
+
+    pub fn set_padding_policy(&mut self, padding: PaddingPolicy) {
+        self.record.set_padding_policy(padding);
+    }
+
+    pub fn set_rekey_policy(&mut self, rekey: RekeyPolicy) {
+        self.record.set_rekey_policy(rekey);
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream.stream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FramedStream, MessageType, SecureChannel};
+    use std::thread;
+
+    #[tokio::test]
+    async fn async_and_blocking_ends_talk_to_each_other() {
+        let private_key: Rsa<Private> = Rsa::generate(2048).unwrap();
+        let public_key: Rsa<Public> =
+            Rsa::public_key_from_pem(&private_key.public_key_to_pem().unwrap()).unwrap();
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        // The blocking end answers whatever it is sent, then says goodbye
+        let blocking = thread::spawn(move || {
+            let mut channel: SecureChannel<std::os::unix::net::UnixStream> =
+                SecureChannel::respond(FramedStream::new(theirs), &private_key).unwrap();
+            let message: Message = channel.recv().unwrap();
+            channel
+                .send_reliable(Message::new(message.content.repeat(2), MessageType::NORMAL))
+                .unwrap();
+            // Picks up the Ack
+            let goodbye: Message = channel.recv().unwrap();
+            assert_eq!(goodbye.message_type, MessageType::Goodbye);
+            assert!(channel.recv().is_err());
+        });
+        ours.set_nonblocking(true).unwrap();
+        let stream: tokio::net::UnixStream = tokio::net::UnixStream::from_std(ours).unwrap();
+        let mut channel: AsyncSecureChannel<tokio::net::UnixStream> =
+            AsyncSecureChannel::initiate(stream, &public_key)
+                .await
+                .unwrap();
+        assert_eq!(
+            channel.session().version,
+            crate::handshake::PROTOCOL_VERSION
+        );
+        assert_eq!(
+            channel.session().features,
+            crate::handshake::SUPPORTED_FEATURES
+        );
+        let text: Vec<u8> = b"-----BEGIN PUBLIC KEY-----\n".repeat(10);
+        channel
+            .send(Message::new(text.clone(), MessageType::InformPublicKey))
+            .await
+            .unwrap();
+        let reply: Message = channel.recv().await.unwrap();
+        assert_eq!(reply.content, text.repeat(2));
+        channel.close(GoodbyeReason::Leaving).await.unwrap();
+        blocking.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_close_the_channel() {
+        let (ours, mut theirs) = tokio::io::duplex(1024);
+        let (session, _) = crate::handshake::test_sessions([2; 32], 0);
+        let mut channel: AsyncSecureChannel<tokio::io::DuplexStream> =
+            AsyncSecureChannel::new(ours, session);
+        channel.set_max_frame_len(100);
+        theirs.write_all(&1000u32.to_be_bytes()).await.unwrap();
+        assert!(matches!(
+            channel.recv().await,
+            Err(ProtocolError::Oversized { .. })
+        ));
+        assert!(matches!(channel.recv().await, Err(ProtocolError::Closed)));
+    }
+}
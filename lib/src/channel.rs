@@ -0,0 +1,660 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::handshake::{self, Session};
+use crate::padding::PaddingPolicy;
+use crate::payloads::{Goodbye, GoodbyeReason, Payload};
+use crate::record::{RecordLayer, RekeyPolicy, TRANSFER_PIECES_PER_TURN};
+use crate::transfer::Progress;
+use crate::{FramedStream, Message, ProtocolError, Transport};
+use openssl::pkey::{Private, Public};
+use openssl::rsa::Rsa;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+// An encrypted connection, holding everything needed to send and receive messages on it.
+// How messages are sealed into frames is up to the RecordLayer (see record.rs), this only moves
+// the frames.
+#[derive(Debug)]
+pub struct SecureChannel<S> {
+    stream: FramedStream<S>,
+    record: RecordLayer,
+}
+
+impl<S: Read + Write> SecureChannel<S> {
+    // Wraps a stream which has already completed the handshake
+    pub fn new(stream: FramedStream<S>, session: Session) -> Self {
+        SecureChannel {
+            stream,
+            record: RecordLayer::new(session),
+        }
+    }
+
+    // Runs the handshake from the connecting side, with the public key of whoever we are connecting to
+    pub fn initiate(
+        mut stream: FramedStream<S>,
+        their_key: &Rsa<Public>,
+    ) -> Result<Self, ProtocolError> {
+        let session: Session = handshake::initiate(&mut stream, their_key)?;
+        Ok(Self::new(stream, session))
+    }
+
+    // Runs the handshake from the listening side, with our own private key
+    pub fn respond(
+        mut stream: FramedStream<S>,
+        our_key: &Rsa<Private>,
+    ) -> Result<Self, ProtocolError> {
+        let session: Session = handshake::respond(&mut stream, our_key)?;
+        Ok(Self::new(stream, session))
+    }
+
+    // A message too large for a frame is queued as a transfer, and goes out a few pieces at a time
+    // with each send and recv after this
+    pub fn send(&mut self, message: Message) -> Result<(), ProtocolError> {
+        self.send_with_id(&message, 0)
+    }
+
+    // Sends a message which is sent again until the other end acknowledges it, returning its ID.
+    // It is kept even if this send fails, so it will still go out if the connection recovers.
+    // A message sent as a transfer is acknowledged once all of it has arrived.
+    pub fn send_reliable(&mut self, message: Message) -> Result<u64, ProtocolError> {
+        let id: u64 = self.record.track(&message);
+        self.send_with_id(&message, id)?;
+        Ok(id)
+    }
+
+    pub fn is_acknowledged(&self, id: u64) -> bool {
+        self.record.is_acknowledged(id)
+    }
+
+    // How much of each transfer we are sending has gone out
+    pub fn outgoing_transfers(&self) -> Vec<Progress> {
+        self.record.outgoing_transfers()
+    }
+
+    // How much of each transfer the other end is sending has arrived
+    pub fn incoming_transfers(&self) -> Vec<Progress> {
+        self.record.incoming_transfers()
+    }
+
+    // How much may be arriving in transfers at once, which starts at SERVER_LINK_MAX_TRANSFER_LEN
+    pub fn set_max_transfer_len(&mut self, max_transfer_len: usize) {
+        self.record.set_max_transfer_len(max_transfer_len);
+    }
+
+    fn send_with_id(&mut self, message: &Message, message_id: u64) -> Result<(), ProtocolError> {
+        self.send_owed_acks()?;
+        if !self.record.fits(message, self.stream.max_frame_len()) {
+            self.record.queue_transfer(message.clone(), message_id);
+            return self.send_transfer_pieces();
+        }
+        // Pieces already queued go first, so nothing follows a Goodbye
+        self.send_transfer_pieces()?;
+        self.write_record(message, message_id)
+    }
+
+    // Only a few pieces go out at a time, so a transfer doesn't hold up everything sent after it
+    fn send_transfer_pieces(&mut self) -> Result<(), ProtocolError> {
+        for _ in 0..TRANSFER_PIECES_PER_TURN {
+            let Some(piece) = self.record.transfer_piece() else {
+                break;
+            };
+            self.write_record(&piece, 0)?;
+            self.record.transfer_piece_sent();
+        }
+        Ok(())
+    }
+
+    // Sends the Acks we owe, keeping any which can't go out yet for next time
+    fn send_owed_acks(&mut self) -> Result<(), ProtocolError> {
+        while let Some(ack) = self.record.owed_ack() {
+            self.write_record(&ack, 0)?;
+            self.record.ack_sent();
+        }
+        Ok(())
+    }
+
+    fn write_record(&mut self, message: &Message, message_id: u64) -> Result<(), ProtocolError> {
+        // Rekeying waits for the next message, so nothing follows a Goodbye
+        if self.record.rekey_due() {
+            let key_update: Vec<u8> = self.record.key_update(self.stream.max_frame_len())?;
+            self.stream.write_frame(&key_update)?;
+            self.record.rekeyed()?;
+        }
+        let frame: Vec<u8> = self
+            .record
+            .seal(message, message_id, self.stream.max_frame_len())?;
+        self.stream.write_frame(&frame)?;
+        self.record.sent(frame.len())
+    }
+
+    // Acks and repeated messages are dealt with here rather than returned. Receiving is also what
+    // drives retransmission, so a channel should be polled even when nothing is expected.
+    pub fn recv(&mut self) -> Result<Message, ProtocolError> {
+        self.send_owed_acks()?;
+        for (id, message) in self.record.overdue() {
+            self.send_with_id(&message, id)?;
+        }
+        self.send_transfer_pieces()?;
+        loop {
+            let frame: Vec<u8> = self.stream.read_frame()?;
+            let message: Option<Message> = self.record.open(&frame, self.stream.max_frame_len())?;
+            // The message has arrived even if its Ack has to wait for the next send or recv
+            let acknowledged: Result<(), ProtocolError> = self.send_owed_acks();
+            match message {
+                Some(message) => return Ok(message),
+                None => acknowledged?,
+            }
+        }
+    }
+
+    // Sends out anything left over from a send which timed out part way through
+    pub fn flush(&mut self) -> Result<(), ProtocolError> {
+        self.stream.flush()
+    }
+
+    pub fn session(&self) -> &Session {
+        self.record.session()
+    }
+
+    // Only changes what we send, the other end strips whatever padding it finds
+    pub fn set_padding_policy(&mut self, padding: PaddingPolicy) {
+        self.record.set_padding_policy(padding);
+    }
+
+    // Also only changes what we send, the other end follows our KeyUpdates
+    pub fn set_rekey_policy(&mut self, rekey: RekeyPolicy) {
+        self.record.set_rekey_policy(rekey);
+    }
+
+    pub fn get_ref(&self) -> &S {
+        self.stream.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        self.stream.get_mut()
+    }
+}
+
+impl<S: Transport> SecureChannel<S> {
+    // Runs the handshake on a connection a listener has just accepted, giving up if the other end
+    // goes quiet for longer than the timeout (usually ACCEPT_HANDSHAKE_TIMEOUT), so it can't hold
+    // up the listener. The timeouts are cleared again once the handshake is done.
+    pub fn respond_within(
+        stream: FramedStream<S>,
+        our_key: &Rsa<Private>,
+        timeout: Duration,
+    ) -> Result<Self, ProtocolError> {
+        stream.get_ref().set_read_timeout(Some(timeout))?;
+        stream.get_ref().set_write_timeout(Some(timeout))?;
+        let channel: Self = Self::respond(stream, our_key)?;
+        channel.get_ref().set_read_timeout(None)?;
+        channel.get_ref().set_write_timeout(None)?;
+        Ok(channel)
+    }
+
+    // Tells the other end why we are going, then closes the connection. The connection is closed
+    // even if the Goodbye can't be sent, as the other end may already be gone.
+    pub fn close(&mut self, reason: GoodbyeReason) -> Result<(), ProtocolError> {
+        let sent: Result<(), ProtocolError> = self
+            .send(Goodbye { reason }.to_message())
+            .and_then(|_| self.flush());
+        self.get_ref().shutdown()?;
+        sent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression;
+    use crate::handshake::{FEATURE_COMPRESSION, FEATURE_PADDING};
+    use crate::payloads::KeyUpdate;
+    use crate::record::RETRANSMIT_AFTER;
+    use crate::transfer::CHUNK_LEN;
+    use crate::{
+        encrypt_aes, encrypt_aes_with_aad, MemoryPipe, MessageHeader, MessageType, Role,
+        SequenceNumbers, AES_OVERHEAD, DEFAULT_PADDING_BUCKETS, MESSAGE_HEADER_LEN,
+        SERVER_LINK_MAX_FRAME_LEN,
+    };
+    use std::io::{self, ErrorKind};
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    fn channel_pair(
+        key: [u8; 32],
+        features: u32,
+    ) -> (SecureChannel<UnixStream>, SecureChannel<UnixStream>) {
+        let (initiator, responder) = UnixStream::pair().unwrap();
+        let (initiator_session, responder_session) = handshake::test_sessions(key, features);
+        (
+            SecureChannel::new(FramedStream::new(initiator), initiator_session),
+            SecureChannel::new(FramedStream::new(responder), responder_session),
+        )
+    }
+
+    #[test]
+    fn headers_are_sealed_with_their_message() {
+        let key: [u8; 32] = [9; 32];
+        let mut tag: [u8; 16] = [0; 16];
+        let (mut writer, mut reader) = channel_pair(key, 0);
+        writer
+            .send(Message::new(b"hello".to_vec(), MessageType::Secret))
+            .unwrap();
+        let message: Message = reader.recv().unwrap();
+        assert_eq!(message.message_type, MessageType::Secret);
+        assert_eq!(message.content, b"hello");
+        // One IV and tag for the whole frame, plus the header
+        let mut record: Vec<u8> = vec![0; MESSAGE_HEADER_LEN];
+        record.extend_from_slice(b"hello");
+        assert_eq!(
+            encrypt_aes(&record, &key, &mut tag).unwrap().len(),
+            AES_OVERHEAD + MESSAGE_HEADER_LEN + 5
+        );
+        // A header whose length disagrees with the sealed body is refused
+        let mut header: Vec<u8> = MessageHeader::new(b"hello world", MessageType::Secret, 0)
+            .as_bytes()
+            .to_vec();
+        header.extend_from_slice(b"hello");
+        let frame: Vec<u8> =
+            encrypt_aes_with_aad(&header, &writer.record.sequence.send_aad(), &key, &mut tag)
+                .unwrap();
+        writer.stream.write_frame(&frame).unwrap();
+        assert!(matches!(reader.recv(), Err(ProtocolError::Malformed(_))));
+    }
+
+    #[test]
+    fn replayed_reordered_and_reflected_frames_are_rejected() {
+        let key: [u8; 32] = [5; 32];
+        let mut tag: [u8; 16] = [0; 16];
+        let (mut initiator, mut responder) = channel_pair(key, 0);
+        let mut record: Vec<u8> = MessageHeader::new(b"one", MessageType::NORMAL, 0)
+            .as_bytes()
+            .to_vec();
+        record.extend_from_slice(b"one");
+        let first: Vec<u8> = encrypt_aes_with_aad(
+            &record,
+            &initiator.record.sequence.send_aad(),
+            &key,
+            &mut tag,
+        )
+        .unwrap();
+        initiator.stream.write_frame(&first).unwrap();
+        initiator.record.sequence.advance_send().unwrap();
+        assert_eq!(responder.recv().unwrap().content, b"one");
+        // The same frame a second time
+        initiator.stream.write_frame(&first).unwrap();
+        assert!(matches!(
+            responder.recv(),
+            Err(ProtocolError::AuthenticationFailed)
+        ));
+        // A frame from further ahead than the next one expected
+        initiator.record.sequence.advance_send().unwrap();
+        initiator
+            .send(Message::new(b"three".to_vec(), MessageType::NORMAL))
+            .unwrap();
+        assert!(matches!(
+            responder.recv(),
+            Err(ProtocolError::AuthenticationFailed)
+        ));
+        // A frame the responder sent, bounced back at it with the number it expects next
+        let mut reflected: SequenceNumbers = SequenceNumbers::new(Role::Responder);
+        reflected.advance_send().unwrap();
+        let frame: Vec<u8> =
+            encrypt_aes_with_aad(&record, &reflected.send_aad(), &key, &mut tag).unwrap();
+        initiator.stream.write_frame(&frame).unwrap();
+        assert!(matches!(
+            responder.recv(),
+            Err(ProtocolError::AuthenticationFailed)
+        ));
+        // None of the rejected frames moved the receiver on
+        let mut resumed: SequenceNumbers = SequenceNumbers::new(Role::Initiator);
+        resumed.advance_send().unwrap();
+        initiator.record.sequence = resumed;
+        initiator
+            .send(Message::new(b"two".to_vec(), MessageType::NORMAL))
+            .unwrap();
+        assert_eq!(responder.recv().unwrap().content, b"two");
+    }
+
+    #[test]
+    fn closing_says_goodbye_first() {
+        let (ours, theirs) = MemoryPipe::pair();
+        let (initiator, responder) = handshake::test_sessions([3; 32], 0);
+        let mut closing: SecureChannel<MemoryPipe> =
+            SecureChannel::new(FramedStream::new(ours), initiator);
+        let mut staying: SecureChannel<MemoryPipe> =
+            SecureChannel::new(FramedStream::new(theirs), responder);
+        closing.close(GoodbyeReason::Leaving).unwrap();
+        let goodbye: Goodbye = Goodbye::from_message(&staying.recv().unwrap()).unwrap();
+        assert_eq!(goodbye.reason, GoodbyeReason::Leaving);
+        assert!(matches!(staying.recv(), Err(ProtocolError::Closed)));
+        // Closing again still shuts the connection, but reports the Goodbye couldn't go out
+        assert!(closing.close(GoodbyeReason::Leaving).is_err());
+    }
+
+    #[test]
+    fn quiet_connections_give_up_on_the_handshake() {
+        let key: Rsa<Private> = Rsa::generate(2048).unwrap();
+        let (ours, _theirs) = MemoryPipe::pair();
+        let started: Instant = Instant::now();
+        assert!(matches!(
+            SecureChannel::respond_within(FramedStream::new(ours), &key, Duration::from_millis(50)),
+            Err(ProtocolError::Timeout)
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn reliable_messages_are_acknowledged_and_deduplicated() {
+        let (mut sender, mut receiver) = channel_pair([7; 32], 0);
+        for channel in [&sender, &receiver] {
+            channel
+                .get_ref()
+                .set_read_timeout(Some(Duration::from_millis(50)))
+                .unwrap();
+        }
+        let id: u64 = sender
+            .send_reliable(Message::new(b"secret".to_vec(), MessageType::Secret))
+            .unwrap();
+        assert!(!sender.is_acknowledged(id));
+        // Pretend the Ack is late, so the message goes out a second time
+        sender.record.unacknowledged[0].sent_at -= RETRANSMIT_AFTER;
+        assert!(matches!(sender.recv(), Err(ProtocolError::Timeout)));
+        assert_eq!(receiver.recv().unwrap().content, b"secret");
+        // The repeat is acknowledged but not handed over again
+        assert!(matches!(receiver.recv(), Err(ProtocolError::Timeout)));
+        assert!(matches!(sender.recv(), Err(ProtocolError::Timeout)));
+        assert!(sender.is_acknowledged(id));
+        // Unacknowledged traffic still arrives in between
+        sender
+            .send(Message::new(b"plain".to_vec(), MessageType::NORMAL))
+            .unwrap();
+        assert_eq!(receiver.recv().unwrap().content, b"plain");
+    }
+
+    #[test]
+    fn messages_too_large_for_a_frame_are_sent_as_transfers() {
+        let (ours, theirs) = MemoryPipe::pair();
+        for pipe in [&ours, &theirs] {
+            pipe.set_read_timeout(Some(Duration::from_millis(50)))
+                .unwrap();
+        }
+        let (initiator, responder) = handshake::test_sessions([4; 32], FEATURE_PADDING);
+        let mut sender: SecureChannel<MemoryPipe> =
+            SecureChannel::new(FramedStream::new(ours), initiator);
+        let mut receiver: SecureChannel<MemoryPipe> =
+            SecureChannel::new(FramedStream::new(theirs), responder);
+        // Twice as many chunks as go out in a turn, which leaves one once the start has gone too
+        let large: Vec<u8> = (0..2 * TRANSFER_PIECES_PER_TURN * CHUNK_LEN)
+            .map(|i| (i * 7) as u8)
+            .collect();
+        sender
+            .send(Message::new(large.clone(), MessageType::NORMAL))
+            .unwrap();
+        // Only part of it goes out with each send, so a later message overtakes it
+        sender
+            .send(Message::new(b"small".to_vec(), MessageType::NORMAL))
+            .unwrap();
+        assert_eq!(receiver.recv().unwrap().content, b"small");
+        let sent: usize = (2 * TRANSFER_PIECES_PER_TURN - 1) * CHUNK_LEN;
+        assert_eq!(sender.outgoing_transfers()[0].done, sent);
+        assert_eq!(sender.outgoing_transfers()[0].total, large.len());
+        assert_eq!(receiver.incoming_transfers()[0].done, sent);
+        // The rest goes out when the sender next polls
+        assert!(matches!(sender.recv(), Err(ProtocolError::Timeout)));
+        assert!(sender.outgoing_transfers().is_empty());
+        let message: Message = receiver.recv().unwrap();
+        assert_eq!(message.message_type, MessageType::NORMAL);
+        assert_eq!(message.content, large);
+        assert!(receiver.incoming_transfers().is_empty());
+        // Sent reliably, it is kept whole and only acknowledged once all of it has arrived
+        let id: u64 = sender
+            .send_reliable(Message::new(large.clone(), MessageType::Secret))
+            .unwrap();
+        assert_eq!(sender.record.unacknowledged.len(), 1);
+        for _ in 0..2 {
+            assert!(matches!(receiver.recv(), Err(ProtocolError::Timeout)));
+            assert!(matches!(sender.recv(), Err(ProtocolError::Timeout)));
+        }
+        assert!(!sender.is_acknowledged(id));
+        assert_eq!(receiver.recv().unwrap().content, large);
+        assert!(matches!(sender.recv(), Err(ProtocolError::Timeout)));
+        assert!(sender.is_acknowledged(id));
+        // Each channel decides how much it will take in at once
+        receiver.set_max_transfer_len(CHUNK_LEN);
+        sender
+            .send(Message::new(large, MessageType::NORMAL))
+            .unwrap();
+        assert!(matches!(receiver.recv(), Err(ProtocolError::Malformed(_))));
+    }
+
+    // A stream whose writes can be made to time out, as if the other end had stopped reading
+    #[derive(Debug)]
+    struct Stalling {
+        stream: UnixStream,
+        stalled: Arc<AtomicBool>,
+    }
+
+    impl Read for Stalling {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.stream.read(buf)
+        }
+    }
+
+    impl Write for Stalling {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.stalled.load(Ordering::Relaxed) {
+                return Err(io::Error::from(ErrorKind::WouldBlock));
+            }
+            self.stream.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.stream.flush()
+        }
+    }
+
+    #[test]
+    fn messages_whose_ack_fails_are_still_delivered_once() {
+        let (initiator, responder) = UnixStream::pair().unwrap();
+        for stream in [&initiator, &responder] {
+            stream
+                .set_read_timeout(Some(Duration::from_millis(50)))
+                .unwrap();
+        }
+        let (initiator_session, responder_session) = handshake::test_sessions([1; 32], 0);
+        let mut sender: SecureChannel<UnixStream> =
+            SecureChannel::new(FramedStream::new(initiator), initiator_session);
+        let stalled: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
+        let receiving: Stalling = Stalling {
+            stream: responder,
+            stalled: stalled.clone(),
+        };
+        let mut receiver: SecureChannel<Stalling> =
+            SecureChannel::new(FramedStream::new(receiving), responder_session);
+        let id: u64 = sender
+            .send_reliable(Message::new(b"secret".to_vec(), MessageType::Secret))
+            .unwrap();
+        // The Ack can't be written, but the message is handed over anyway
+        assert_eq!(receiver.recv().unwrap().content, b"secret");
+        // So the sender tries again
+        stalled.store(false, Ordering::Relaxed);
+        sender.record.unacknowledged[0].sent_at -= RETRANSMIT_AFTER;
+        assert!(matches!(sender.recv(), Err(ProtocolError::Timeout)));
+        // The owed Ack goes out, and the repeat is acknowledged without being handed over again
+        assert!(matches!(receiver.recv(), Err(ProtocolError::Timeout)));
+        assert!(matches!(sender.recv(), Err(ProtocolError::Timeout)));
+        assert!(sender.is_acknowledged(id));
+        sender
+            .send(Message::new(b"next".to_vec(), MessageType::NORMAL))
+            .unwrap();
+        assert_eq!(receiver.recv().unwrap().content, b"next");
+    }
+
+    #[test]
+    fn reliable_messages_overtaken_by_later_ones_are_delivered_once() {
+        let (initiator, responder) = UnixStream::pair().unwrap();
+        for stream in [&initiator, &responder] {
+            stream
+                .set_read_timeout(Some(Duration::from_millis(50)))
+                .unwrap();
+        }
+        let (initiator_session, responder_session) = handshake::test_sessions([2; 32], 0);
+        let stalled: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
+        let sending: Stalling = Stalling {
+            stream: initiator,
+            stalled: stalled.clone(),
+        };
+        let mut sender: SecureChannel<Stalling> =
+            SecureChannel::new(FramedStream::new(sending), initiator_session);
+        let mut receiver: SecureChannel<UnixStream> =
+            SecureChannel::new(FramedStream::new(responder), responder_session);
+        // The first message can't be written, so the second overtakes it
+        assert!(sender
+            .send_reliable(Message::new(b"first".to_vec(), MessageType::Secret))
+            .is_err());
+        stalled.store(false, Ordering::Relaxed);
+        let second: u64 = sender
+            .send_reliable(Message::new(b"second".to_vec(), MessageType::Secret))
+            .unwrap();
+        assert_eq!(second, 2);
+        assert_eq!(receiver.recv().unwrap().content, b"second");
+        // The first is still handed over when it is sent again
+        sender.record.unacknowledged[0].sent_at -= RETRANSMIT_AFTER;
+        assert!(matches!(sender.recv(), Err(ProtocolError::Timeout)));
+        assert_eq!(receiver.recv().unwrap().content, b"first");
+        // But only the once
+        sender.record.unacknowledged[0].sent_at -= RETRANSMIT_AFTER;
+        assert!(matches!(sender.recv(), Err(ProtocolError::Timeout)));
+        assert!(matches!(receiver.recv(), Err(ProtocolError::Timeout)));
+        assert!(matches!(sender.recv(), Err(ProtocolError::Timeout)));
+        assert!(sender.is_acknowledged(1));
+        assert!(sender.is_acknowledged(second));
+    }
+
+    #[test]
+    fn bodies_are_compressed_when_negotiated() {
+        let key: [u8; 32] = [6; 32];
+        let mut tag: [u8; 16] = [0; 16];
+        let (mut sender, mut receiver) = channel_pair(key, FEATURE_COMPRESSION);
+        let key_text: Vec<u8> = b"-----BEGIN PUBLIC KEY-----\n".repeat(40);
+        sender
+            .send(Message::new(key_text.clone(), MessageType::InformPublicKey))
+            .unwrap();
+        sender
+            .send(Message::new(b"short".to_vec(), MessageType::NORMAL))
+            .unwrap();
+        let frame: Vec<u8> = receiver.stream.read_frame().unwrap();
+        assert!(frame.len() < key_text.len() / 2);
+        receiver.record.sequence.advance_receive().unwrap();
+        assert_eq!(receiver.recv().unwrap().content, b"short");
+        // A small frame which would expand past the frame limit is refused before expanding it
+        let bomb: Vec<u8> = vec![0; 2 * SERVER_LINK_MAX_FRAME_LEN];
+        let mut record: Vec<u8> = MessageHeader::new(&bomb, MessageType::NORMAL, 0)
+            .as_bytes()
+            .to_vec();
+        record.extend_from_slice(&compression::encode(&bomb));
+        let frame: Vec<u8> =
+            encrypt_aes_with_aad(&record, &sender.record.sequence.send_aad(), &key, &mut tag)
+                .unwrap();
+        sender.stream.write_frame(&frame).unwrap();
+        assert!(matches!(
+            receiver.recv(),
+            Err(ProtocolError::Oversized { .. })
+        ));
+        // Nor will one be sent, it goes as a transfer instead
+        sender.record.sequence.advance_send().unwrap();
+        sender
+            .send(Message::new(bomb.clone(), MessageType::NORMAL))
+            .unwrap();
+        assert_eq!(receiver.recv().unwrap().content, bomb);
+    }
+
+    #[test]
+    fn padded_frames_hide_their_lengths() {
+        let (mut sender, mut receiver) = channel_pair([8; 32], FEATURE_PADDING);
+        let messages: [Message; 3] = [
+            Message::new(vec![1; 8], MessageType::Ping),
+            Message::new(b"a".to_vec(), MessageType::Secret),
+            Message::new(b"a much longer name".to_vec(), MessageType::Secret),
+        ];
+        let mut lengths: Vec<usize> = Vec::new();
+        for message in messages.iter() {
+            sender.send(message.clone()).unwrap();
+            let frame: Vec<u8> = receiver.stream.read_frame().unwrap();
+            lengths.push(frame.len());
+            // Put back so the receiver can open it as usual
+            sender.stream.write_frame(&frame).unwrap();
+            assert_eq!(receiver.recv().unwrap().content, message.content);
+        }
+        assert_eq!(lengths, [AES_OVERHEAD + DEFAULT_PADDING_BUCKETS[0]; 3]);
+        // Without padding the lengths show through
+        sender.set_padding_policy(PaddingPolicy::none());
+        sender.send(messages[1].clone()).unwrap();
+        let frame: Vec<u8> = receiver.stream.read_frame().unwrap();
+        assert_eq!(frame.len(), AES_OVERHEAD + MESSAGE_HEADER_LEN + 1 + 1);
+    }
+
+    #[test]
+    fn keys_move_on_once_the_rekey_limit_is_reached() {
+        let key: [u8; 32] = [5; 32];
+        let (mut sender, mut receiver) = channel_pair(key, 0);
+        sender.set_rekey_policy(RekeyPolicy {
+            max_records: 2,
+            max_bytes: u64::MAX,
+        });
+        for i in 0..5u8 {
+            sender
+                .send(Message::new(vec![i], MessageType::NORMAL))
+                .unwrap();
+            assert_eq!(receiver.recv().unwrap().content, [i]);
+        }
+        // Rekeyed after the second and fourth messages, in our direction only
+        let rekeyed: [u8; 32] = handshake::next_key(&handshake::next_key(&key).unwrap()).unwrap();
+        assert_eq!(sender.session().send_key, rekeyed);
+        assert_eq!(receiver.session().receive_key, rekeyed);
+        assert_eq!(receiver.session().send_key, key);
+        // Or after any bytes at all
+        receiver.set_rekey_policy(RekeyPolicy {
+            max_records: u64::MAX,
+            max_bytes: 1,
+        });
+        for _ in 0..2 {
+            receiver
+                .send(Message::new(b"back".to_vec(), MessageType::NORMAL))
+                .unwrap();
+            assert_eq!(sender.recv().unwrap().content, b"back");
+        }
+        // Rekeyed before the second, as the first went past the limit
+        assert_eq!(
+            sender.session().receive_key,
+            handshake::next_key(&key).unwrap()
+        );
+        // A KeyUpdate which skips a generation is refused
+        let frame: Vec<u8> = sender
+            .record
+            .seal(
+                &KeyUpdate { generation: 9 }.to_message(),
+                0,
+                SERVER_LINK_MAX_FRAME_LEN,
+            )
+            .unwrap();
+        sender.stream.write_frame(&frame).unwrap();
+        assert!(matches!(receiver.recv(), Err(ProtocolError::Malformed(_))));
+    }
+}
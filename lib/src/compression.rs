@@ -0,0 +1,103 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Compressing message bodies, on connections which negotiated handshake::FEATURE_COMPRESSION.
+// The body in such a record starts with a byte saying how the rest is encoded:
+//
+//   0  as it is
+//   1  Snappy raw format
+//
+// Bodies are compressed before encryption, and only when it makes them smaller.
+
+use crate::ProtocolError;
+use snap::raw::{decompress_len, Decoder, Encoder};
+
+const UNCOMPRESSED: u8 = 0;
+const SNAPPY: u8 = 1;
+// Smaller bodies are never worth it
+const MIN_COMPRESSED_LEN: usize = 64;
+
+pub fn encode(content: &[u8]) -> Vec<u8> {
+    if content.len() >= MIN_COMPRESSED_LEN {
+        if let Ok(compressed) = Encoder::new().compress_vec(content) {
+            if compressed.len() + 1 < content.len() {
+                let mut body: Vec<u8> = Vec::with_capacity(compressed.len() + 1);
+                body.push(SNAPPY);
+                body.extend_from_slice(&compressed);
+                return body;
+            }
+        }
+    }
+    let mut body: Vec<u8> = Vec::with_capacity(content.len() + 1);
+    body.push(UNCOMPRESSED);
+    body.extend_from_slice(content);
+    body
+}
+
+// The length the body claims to expand to is checked against max_len before anything is
+// decompressed, so a small frame can't expand into more than the largest frame allowed.
+pub fn decode(body: &[u8], max_len: usize) -> Result<Vec<u8>, ProtocolError> {
+    match body.split_first() {
+        Some((&UNCOMPRESSED, content)) => Ok(content.to_vec()),
+        Some((&SNAPPY, compressed)) => {
+            let len: usize = decompress_len(compressed)
+                .map_err(|err| ProtocolError::Malformed(err.to_string()))?;
+            if len > max_len {
+                return Err(ProtocolError::Oversized { len, max: max_len });
+            }
+            Decoder::new()
+                .decompress_vec(compressed)
+                .map_err(|err| ProtocolError::Malformed(err.to_string()))
+        }
+        Some((encoding, _)) => Err(ProtocolError::Malformed(format!(
+            "unknown body encoding {}",
+            encoding
+        ))),
+        None => Err(ProtocolError::Malformed(
+            "body encoding is missing".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_worthwhile_bodies_are_compressed_and_bombs_are_refused() {
+        let text: Vec<u8> = b"-----BEGIN PUBLIC KEY-----\n".repeat(20);
+        let body: Vec<u8> = encode(&text);
+        assert_eq!(body[0], SNAPPY);
+        assert!(body.len() < text.len() / 2);
+        assert_eq!(decode(&body, 1024).unwrap(), text);
+        // Short or incompressible bodies go as they are
+        let short: Vec<u8> = encode(b"hi");
+        assert_eq!(short, b"\0hi");
+        assert_eq!(decode(&short, 1024).unwrap(), b"hi");
+        let noise: Vec<u8> = (0..200u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        assert_eq!(encode(&noise)[0], UNCOMPRESSED);
+        // A megabyte of zeroes compresses to a few kilobytes, but must not be expanded past the limit
+        let bomb: Vec<u8> = encode(&vec![0; 1024 * 1024]);
+        assert!(bomb.len() < 64 * 1024);
+        assert!(matches!(
+            decode(&bomb, 64 * 1024),
+            Err(ProtocolError::Oversized { .. })
+        ));
+        assert!(decode(&[7, 1, 2], 1024).is_err());
+        assert!(decode(&[], 1024).is_err());
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use openssl::error::ErrorStack;
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    // Any I/O failure which isn't a timeout or the connection closing
+    Io(io::Error),
+    // Nothing arrived before the stream's read timeout, the connection is still usable
+    Timeout,
+    // The other end closed the connection
+    Closed,
+    // A frame failed to decrypt, either because it was tampered with or the key is wrong
+    AuthenticationFailed,
+    UnknownMessageType(u8),
+    Oversized { len: usize, max: usize },
+    // A frame decrypted fine but its contents did not make sense
+    Malformed(String),
+    // The other end refused the handshake, with its reason why
+    Rejected(String),
+    // The other end of the handshake couldn't prove it holds the key we expected it to, so it is
+    // either an impostor or we have the wrong key for it
+    KeyNotConfirmed,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "I/O error: {}", err),
+            Self::Timeout => write!(f, "timed out waiting for the connection"),
+            Self::Closed => write!(f, "connection closed"),
+            Self::AuthenticationFailed => write!(f, "message failed authentication"),
+            Self::UnknownMessageType(byte) => write!(f, "unknown message type {}", byte),
+            Self::Oversized { len, max } => {
+                write!(f, "frame of {} bytes exceeds the maximum of {}", len, max)
+            }
+            Self::Malformed(reason) => write!(f, "malformed message: {}", reason),
+            Self::Rejected(reason) => write!(f, "handshake rejected: {}", reason),
+            Self::KeyNotConfirmed => {
+                write!(f, "the other end could not prove it holds the expected key")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => Self::Timeout,
+            ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::NotConnected
+            | ErrorKind::WriteZero => Self::Closed,
+            _ => Self::Io(err),
+        }
+    }
+}
+
+// OpenSSL only fails on our side for reasons outside the protocol (e.g. no randomness available)
+impl From<ErrorStack> for ProtocolError {
+    fn from(err: ErrorStack) -> Self {
+        Self::Io(io::Error::other(err))
+    }
+}
@@ -0,0 +1,328 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::ProtocolError;
+use std::io::{self, ErrorKind, Read, Write};
+
+// Every frame on the wire is a 4 byte big-endian length followed by that many bytes of payload
+const LENGTH_PREFIX_LEN: usize = 4;
+pub(crate) const READ_CHUNK_LEN: usize = 4096;
+
+// The largest frame accepted from the server link, which only carries keys, addresses and secrets
+pub const SERVER_LINK_MAX_FRAME_LEN: usize = 64 * 1024;
+// The largest frame accepted from a peer link, which has room for bigger payloads
+pub const PEER_LINK_MAX_FRAME_LEN: usize = 1024 * 1024;
+// How much of the messages too large for a frame may be arriving at once on each kind of link
+// (see transfer.rs)
+pub const SERVER_LINK_MAX_TRANSFER_LEN: usize = 1024 * 1024;
+pub const PEER_LINK_MAX_TRANSFER_LEN: usize = 128 * 1024 * 1024;
+
+// Wraps a byte stream so that reads and writes always deal in whole frames.
+// Partial reads (for example when a read timeout fires half way through a frame) are kept in a
+// buffer and resumed on the next call, so a timeout never desynchronises the stream.
+// Frames longer than max_frame_len are refused in both directions. Receiving one closes the
+// stream, as there is no way to skip the frame without reading it all.
+#[derive(Debug)]
+pub struct FramedStream<S> {
+    stream: S,
+    reader: FrameReader,
+    write_buffer: Vec<u8>,
+    max_frame_len: usize,
+}
+
+impl<S: Read + Write> FramedStream<S> {
+    pub fn new(stream: S) -> Self {
+        Self::with_max_frame_len(stream, SERVER_LINK_MAX_FRAME_LEN)
+    }
+
+    pub fn with_max_frame_len(stream: S, max_frame_len: usize) -> Self {
+        FramedStream {
+            stream,
+            reader: FrameReader::default(),
+            write_buffer: Vec::new(),
+            max_frame_len,
+        }
+    }
+
+    pub fn max_frame_len(&self) -> usize {
+        self.max_frame_len
+    }
+
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.max_frame_len = max_frame_len;
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    // Writes a single frame.
+    // If the write times out before any of the frame is written, nothing is queued and the error
+    // is returned. If it times out part way through, the remainder is kept and sent before any
+    // later frame, so the frame is still delivered whole.
+    pub fn write_frame(&mut self, payload: &[u8]) -> Result<(), ProtocolError> {
+        let frame: Vec<u8> = encode_frame(payload, self.max_frame_len)?;
+        self.flush()?;
+        let mut written: usize = 0;
+        while written < frame.len() {
+            match self.stream.write(&frame[written..]) {
+                Ok(0) => return Err(ProtocolError::Closed),
+                Ok(n) => written += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) if written > 0 && is_timeout(&err) => {
+                    self.write_buffer.extend_from_slice(&frame[written..]);
+                    return Ok(());
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(self.stream.flush()?)
+    }
+
+    // Sends any bytes left over from a frame whose write previously timed out
+    pub fn flush(&mut self) -> Result<(), ProtocolError> {
+        if self.reader.is_closed() {
+            return Err(ProtocolError::Closed);
+        }
+        while !self.write_buffer.is_empty() {
+            match self.stream.write(&self.write_buffer) {
+                Ok(0) => return Err(ProtocolError::Closed),
+                Ok(n) => {
+                    self.write_buffer.drain(..n);
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(self.stream.flush()?)
+    }
+
+    // Reads a single frame, blocking (subject to the stream's read timeout) until it is complete.
+    // A timeout is reported as ProtocolError::Timeout and keeps any partial frame for next time,
+    // the stream ending (whether between frames or part way through one) as ProtocolError::Closed.
+    pub fn read_frame(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        loop {
+            if let Some(frame) = self.reader.next_frame(self.max_frame_len)? {
+                return Ok(frame);
+            }
+            let mut chunk: [u8; READ_CHUNK_LEN] = [0; READ_CHUNK_LEN];
+            match self.stream.read(&mut chunk) {
+                Ok(n) => self.reader.received(&chunk[..n])?,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+// The incoming side of a framed stream, kept apart from the I/O so FramedStream and the async
+// channel's stream split what they read into frames the same way
+#[derive(Debug, Default)]
+pub(crate) struct FrameReader {
+    buffer: Vec<u8>,
+    closed: bool,
+}
+
+impl FrameReader {
+    // The next frame, if all of it has been read. A frame longer than max_frame_len closes the
+    // reader, as there is no way to skip it without reading it all.
+    pub fn next_frame(&mut self, max_frame_len: usize) -> Result<Option<Vec<u8>>, ProtocolError> {
+        if self.closed {
+            return Err(ProtocolError::Closed);
+        }
+        let taken: Result<Option<Vec<u8>>, ProtocolError> =
+            take_frame(&mut self.buffer, max_frame_len);
+        if taken.is_err() {
+            self.closed = true;
+            self.buffer = Vec::new();
+        }
+        taken
+    }
+
+    // Adds the bytes from one read, where reading nothing means the stream has ended
+    pub fn received(&mut self, bytes: &[u8]) -> Result<(), ProtocolError> {
+        if bytes.is_empty() {
+            return Err(ProtocolError::Closed);
+        }
+        self.buffer.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+// The length prefix and payload of a frame, ready to be written
+pub(crate) fn encode_frame(payload: &[u8], max_frame_len: usize) -> Result<Vec<u8>, ProtocolError> {
+    if payload.len() > max_frame_len {
+        return Err(ProtocolError::Oversized {
+            len: payload.len(),
+            max: max_frame_len,
+        });
+    }
+    let payload_len: u32 = u32::try_from(payload.len()).map_err(|_| ProtocolError::Oversized {
+        len: payload.len(),
+        max: u32::MAX as usize,
+    })?;
+    let mut frame: Vec<u8> = Vec::with_capacity(LENGTH_PREFIX_LEN + payload.len());
+    frame.extend_from_slice(&payload_len.to_be_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+// Takes the first frame out of what has been read so far, if all of it has arrived
+fn take_frame(
+    buffer: &mut Vec<u8>,
+    max_frame_len: usize,
+) -> Result<Option<Vec<u8>>, ProtocolError> {
+    if buffer.len() < LENGTH_PREFIX_LEN {
+        return Ok(None);
+    }
+    let mut length_bytes: [u8; LENGTH_PREFIX_LEN] = [0; LENGTH_PREFIX_LEN];
+    length_bytes.copy_from_slice(&buffer[..LENGTH_PREFIX_LEN]);
+    let frame_len: usize = u32::from_be_bytes(length_bytes) as usize;
+    // Checked before waiting for the rest of the frame, so nothing of that size is ever buffered
+    if frame_len > max_frame_len {
+        return Err(ProtocolError::Oversized {
+            len: frame_len,
+            max: max_frame_len,
+        });
+    }
+    if buffer.len() < LENGTH_PREFIX_LEN + frame_len {
+        return Ok(None);
+    }
+    let frame: Vec<u8> = buffer[LENGTH_PREFIX_LEN..LENGTH_PREFIX_LEN + frame_len].to_vec();
+    buffer.drain(..LENGTH_PREFIX_LEN + frame_len);
+    Ok(Some(frame))
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    // A stream which hands out its input a few bytes at a time, with timeouts in between
+    struct TrickleStream {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+        timeout_next: bool,
+    }
+
+    impl Read for TrickleStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.timeout_next = !self.timeout_next;
+            if self.timeout_next {
+                return Err(io::Error::new(ErrorKind::WouldBlock, "timed out"));
+            }
+            let n = buf.len().min(3).min(self.input.len());
+            for byte in buf.iter_mut().take(n) {
+                *byte = self.input.pop_front().unwrap();
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for TrickleStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(5);
+            self.output.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn frames_survive_short_reads_and_writes() {
+        let mut writer = FramedStream::new(TrickleStream {
+            input: VecDeque::new(),
+            output: Vec::new(),
+            timeout_next: false,
+        });
+        writer.write_frame(b"hello there").unwrap();
+        writer.write_frame(b"").unwrap();
+        writer.write_frame(b"general kenobi").unwrap();
+        let mut reader = FramedStream::new(TrickleStream {
+            input: writer.get_ref().output.clone().into(),
+            output: Vec::new(),
+            timeout_next: false,
+        });
+        let mut frames: Vec<Vec<u8>> = Vec::new();
+        while frames.len() < 3 {
+            match reader.read_frame() {
+                Ok(frame) => frames.push(frame),
+                Err(err) => assert!(matches!(err, ProtocolError::Timeout)),
+            }
+        }
+        assert_eq!(
+            frames,
+            vec![b"hello there".to_vec(), vec![], b"general kenobi".to_vec()]
+        );
+        loop {
+            match reader.read_frame() {
+                Err(ProtocolError::Timeout) => continue,
+                Err(ProtocolError::Closed) => break,
+                other => panic!("Expected the connection to close, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_frames_close_the_stream() {
+        let mut writer = FramedStream::with_max_frame_len(
+            TrickleStream {
+                input: VecDeque::new(),
+                output: Vec::new(),
+                timeout_next: false,
+            },
+            16,
+        );
+        assert!(matches!(
+            writer.write_frame(&[0; 17]),
+            Err(ProtocolError::Oversized { len: 17, max: 16 })
+        ));
+        writer.set_max_frame_len(64);
+        writer.write_frame(&[0; 17]).unwrap();
+        writer.write_frame(&[0; 3]).unwrap();
+        let mut reader = FramedStream::with_max_frame_len(
+            TrickleStream {
+                input: writer.get_ref().output.clone().into(),
+                output: Vec::new(),
+                timeout_next: false,
+            },
+            16,
+        );
+        loop {
+            match reader.read_frame() {
+                Err(ProtocolError::Timeout) => continue,
+                Err(ProtocolError::Oversized { len: 17, max: 16 }) => break,
+                other => panic!("Expected the frame to be refused, got {:?}", other),
+            }
+        }
+        // The small frame after it is never read, the stream has been given up on
+        assert!(matches!(reader.read_frame(), Err(ProtocolError::Closed)));
+    }
+}
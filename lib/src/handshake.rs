@@ -0,0 +1,797 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The start of every connection, before any encrypted messages are sent:
+//
+//   initiator -> responder: Hello       MAGIC | min version u16 | max version u16 | features u32 |
+//                                       suite count u8 | cipher suite IDs u16, best first
+//   responder -> initiator: HelloReply  MAGIC | 0 | chosen version u16 | chosen features u32 |
+//                                       chosen suite u16
+//                       or              MAGIC | 1 | UTF-8 reason for the rejection
+//   initiator -> responder: KeyShare    X25519: initiator's ephemeral public key
+//                                       RSA-OAEP: a random secret, encrypted to the responder's key
+//   responder -> initiator: KeyShare    nonce, then
+//                                       X25519: responder's ephemeral public key, confirmation,
+//                                       signature
+//                                       RSA-OAEP: confirmation, signature
+//
+// All integers are big-endian. The responder picks the highest version both ends support, the
+// features both ends support, and the first of the initiator's suites it supports (see suites.rs).
+// The transcript hash is SHA-256(Hello | HelloReply | both key shares). The responder signs it
+// with RSA-PSS using its long-term key, so the initiator knows who answered and that nobody on the
+// path altered either hello (e.g. to force an older version or weaker suite). The confirmation is
+// an HMAC-SHA256 of the transcript hash under a key derived from the agreed secret, so the
+// initiator also knows the responder arrived at the same secret before sending anything under it.
+// If either check fails the handshake ends with KeyNotConfirmed.
+// A key for each direction and a pair secret come from the agreed secret via HKDF, salted with the
+// transcript hash. The responder's random nonce is part of the transcript, so replaying a recorded
+// initiator gets different keys, even with RSA-OAEP where the secret is the initiator's alone.
+// With X25519 both ephemeral keys are thrown away afterwards, so a recorded session can't be
+// decrypted even if the long-term key leaks later.
+
+use crate::kex::{hkdf_sha256, EphemeralKey, KEY_SHARE_LEN};
+use crate::suites::{CipherSuite, KeyExchange};
+use crate::{decrypt_rsa, encrypt_rsa, sign_rsa, verify_rsa, FramedStream, ProtocolError, Role};
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::rand::rand_bytes;
+use openssl::rsa::Rsa;
+use openssl::sha::Sha256;
+use openssl::sign::Signer;
+use std::io::{Read, Write};
+
+pub const MAGIC: [u8; 4] = *b"CRSH";
+// Version 2 replaced the ad-hoc string payloads with the encodings in payloads.rs
+// Version 3 sealed each message header together with its body
+// Version 4 bound per-direction sequence numbers into every record
+// Version 5 added the Ping and Pong heartbeat messages
+// Version 6 added the Goodbye message
+// Version 7 added message IDs to the header and the Ack message
+// Version 8 added the Request and Reply messages for calls
+// Version 9 added logical channels
+// Version 10 added chunked transfers
+// Version 11 replaced RSA key transport with a signed ephemeral X25519 exchange
+// Version 12 added cipher suite negotiation
+// Version 13 split the session key by direction and added the KeyUpdate message
+// Version 14 added the responder's key confirmation
+// Version 15 added the responder's nonce and dropped logical channels
+pub const PROTOCOL_VERSION: u16 = 15;
+pub const MIN_PROTOCOL_VERSION: u16 = 15;
+// Optional capabilities, negotiated as a bitmask
+// Message bodies may be compressed (see compression.rs)
+pub const FEATURE_COMPRESSION: u32 = 1 << 0;
+// Records are padded to hide their length (see padding.rs)
+pub const FEATURE_PADDING: u32 = 1 << 1;
+pub const SUPPORTED_FEATURES: u32 = FEATURE_COMPRESSION | FEATURE_PADDING;
+
+// Up to the suite count
+const HELLO_FIXED_LEN: usize = 13;
+const ACCEPT_LEN: usize = 13;
+const ACCEPT: u8 = 0;
+const REJECT: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub features: u32,
+    // IDs rather than suites, as the other end may offer some we don't know
+    pub suites: Vec<u16>,
+}
+
+impl Hello {
+    pub fn ours() -> Self {
+        Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            features: SUPPORTED_FEATURES,
+            suites: CipherSuite::preferred()
+                .iter()
+                .map(|suite| suite.id)
+                .collect(),
+        }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = MAGIC.to_vec();
+        bytes.extend_from_slice(&self.min_version.to_be_bytes());
+        bytes.extend_from_slice(&self.max_version.to_be_bytes());
+        bytes.extend_from_slice(&self.features.to_be_bytes());
+        // Only room to count 255, so any suites past that are the least preferred and left out
+        let count: u8 = self.suites.len().min(u8::MAX as usize) as u8;
+        bytes.push(count);
+        for suite in self.suites.iter().take(count as usize) {
+            bytes.extend_from_slice(&suite.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        if bytes.len() < HELLO_FIXED_LEN
+            || bytes[..4] != MAGIC
+            || bytes.len() != HELLO_FIXED_LEN + 2 * bytes[12] as usize
+        {
+            return Err(ProtocolError::Malformed(
+                "connection did not start with a hello".to_string(),
+            ));
+        }
+        Ok(Hello {
+            min_version: u16::from_be_bytes([bytes[4], bytes[5]]),
+            max_version: u16::from_be_bytes([bytes[6], bytes[7]]),
+            features: u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            suites: bytes[HELLO_FIXED_LEN..]
+                .chunks_exact(2)
+                .map(|id| u16::from_be_bytes([id[0], id[1]]))
+                .collect(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HelloReply {
+    Accept {
+        version: u16,
+        features: u32,
+        suite: u16,
+    },
+    Reject {
+        reason: String,
+    },
+}
+
+impl HelloReply {
+    // Decides how to answer a hello, given what we support ourselves
+    pub fn answer(ours: &Hello, theirs: &Hello) -> Self {
+        let version: u16 = ours.max_version.min(theirs.max_version);
+        if version < ours.min_version || version < theirs.min_version {
+            return HelloReply::Reject {
+                reason: format!(
+                    "unsupported protocol version: this end supports {}..={}, the other end offered {}..={}",
+                    ours.min_version, ours.max_version, theirs.min_version, theirs.max_version
+                ),
+            };
+        }
+        // Their preference, as what suits their hardware matters more than what suits ours
+        let Some(suite) = theirs
+            .suites
+            .iter()
+            .find(|suite| ours.suites.contains(suite))
+        else {
+            return HelloReply::Reject {
+                reason: "no cipher suite in common".to_string(),
+            };
+        };
+        HelloReply::Accept {
+            version,
+            features: ours.features & theirs.features,
+            suite: *suite,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = MAGIC.to_vec();
+        match self {
+            HelloReply::Accept {
+                version,
+                features,
+                suite,
+            } => {
+                bytes.push(ACCEPT);
+                bytes.extend_from_slice(&version.to_be_bytes());
+                bytes.extend_from_slice(&features.to_be_bytes());
+                bytes.extend_from_slice(&suite.to_be_bytes());
+            }
+            HelloReply::Reject { reason } => {
+                bytes.push(REJECT);
+                bytes.extend_from_slice(reason.as_bytes());
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        if bytes.len() < 5 || bytes[..4] != MAGIC {
+            return Err(ProtocolError::Malformed(
+                "the other end did not answer with a hello".to_string(),
+            ));
+        }
+        match bytes[4] {
+            ACCEPT if bytes.len() == ACCEPT_LEN => Ok(HelloReply::Accept {
+                version: u16::from_be_bytes([bytes[5], bytes[6]]),
+                features: u32::from_be_bytes([bytes[7], bytes[8], bytes[9], bytes[10]]),
+                suite: u16::from_be_bytes([bytes[11], bytes[12]]),
+            }),
+            REJECT => Ok(HelloReply::Reject {
+                reason: String::from_utf8_lossy(&bytes[5..]).to_string(),
+            }),
+            _ => Err(ProtocolError::Malformed("invalid hello reply".to_string())),
+        }
+    }
+}
+
+// What both ends agreed on once the handshake is complete
+#[derive(Debug, Clone)]
+pub struct Session {
+    // Seals what we send, and is replaced each time we rekey
+    pub send_key: [u8; 32],
+    // Opens what the other end sends, and is replaced each time it rekeys
+    pub receive_key: [u8; 32],
+    // The same at both ends and never used on the connection itself, for whatever else the two
+    // ends need a shared secret for
+    pub pair_secret: [u8; 32],
+    pub version: u16,
+    pub features: u32,
+    pub suite: CipherSuite,
+    pub role: Role,
+}
+
+const INITIATOR_KEY_INFO: &[u8] = b"crush initiator to responder";
+const RESPONDER_KEY_INFO: &[u8] = b"crush responder to initiator";
+const PAIR_SECRET_INFO: &[u8] = b"crush pair secret";
+const KEY_UPDATE_INFO: &[u8] = b"crush key update";
+const CONFIRMATION_INFO: &[u8] = b"crush key confirmation";
+const CONFIRMATION_LEN: usize = 32;
+const NONCE_LEN: usize = 32;
+
+fn transcript_hash(
+    hello: &[u8],
+    reply: &[u8],
+    initiator_share: &[u8],
+    responder_share: &[u8],
+) -> [u8; 32] {
+    let mut hasher: Sha256 = Sha256::new();
+    hasher.update(hello);
+    hasher.update(reply);
+    hasher.update(initiator_share);
+    hasher.update(responder_share);
+    hasher.finish()
+}
+
+impl Session {
+    // One end of the session, with each key derived under its own label so none of them gives away
+    // another
+    fn derive(
+        secret: &[u8; 32],
+        transcript: &[u8; 32],
+        version: u16,
+        features: u32,
+        suite: CipherSuite,
+        role: Role,
+    ) -> Result<Session, ProtocolError> {
+        let mut initiator_key: [u8; 32] = [0; 32];
+        let mut responder_key: [u8; 32] = [0; 32];
+        let mut pair_secret: [u8; 32] = [0; 32];
+        hkdf_sha256(secret, transcript, INITIATOR_KEY_INFO, &mut initiator_key)?;
+        hkdf_sha256(secret, transcript, RESPONDER_KEY_INFO, &mut responder_key)?;
+        hkdf_sha256(secret, transcript, PAIR_SECRET_INFO, &mut pair_secret)?;
+        let (send_key, receive_key) = match role {
+            Role::Initiator => (initiator_key, responder_key),
+            Role::Responder => (responder_key, initiator_key),
+        };
+        Ok(Session {
+            send_key,
+            receive_key,
+            pair_secret,
+            version,
+            features,
+            suite,
+            role,
+        })
+    }
+}
+
+// Both ends of a session as if they had just completed the handshake, for tests which only need
+// records to pass between them. Every key is the given key, so frames can be opened by hand.
+#[cfg(test)]
+pub(crate) fn test_sessions(key: [u8; 32], features: u32) -> (Session, Session) {
+    let session = |role: Role| Session {
+        send_key: key,
+        receive_key: key,
+        pair_secret: key,
+        version: PROTOCOL_VERSION,
+        features,
+        suite: CipherSuite::default(),
+        role,
+    };
+    (session(Role::Initiator), session(Role::Responder))
+}
+
+// Proves knowledge of the secret agreed for this transcript, without giving anything away about it
+fn key_confirmation(
+    secret: &[u8; 32],
+    transcript: &[u8; 32],
+) -> Result<[u8; CONFIRMATION_LEN], ProtocolError> {
+    let mut confirmation_key: [u8; 32] = [0; 32];
+    hkdf_sha256(secret, transcript, CONFIRMATION_INFO, &mut confirmation_key)?;
+    let key: PKey<Private> = PKey::hmac(&confirmation_key)?;
+    let mut signer: Signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(transcript)?;
+    let mut confirmation: [u8; CONFIRMATION_LEN] = [0; CONFIRMATION_LEN];
+    signer.sign(&mut confirmation)?;
+    Ok(confirmation)
+}
+
+// The key which replaces this one when its direction is rekeyed. Nothing sealed with the new key
+// tells anything about the old one.
+pub(crate) fn next_key(key: &[u8; 32]) -> Result<[u8; 32], ProtocolError> {
+    let mut next: [u8; 32] = [0; 32];
+    hkdf_sha256(key, &[], KEY_UPDATE_INFO, &mut next)?;
+    Ok(next)
+}
+
+// Runs the handshake from the connecting side, with the public key of whoever we are connecting to
+pub fn initiate<S: Read + Write>(
+    stream: &mut FramedStream<S>,
+    their_key: &Rsa<Public>,
+) -> Result<Session, ProtocolError> {
+    let hello: Vec<u8> = Hello::ours().as_bytes();
+    stream.write_frame(&hello)?;
+    let reply_bytes: Vec<u8> = stream.read_frame()?;
+    let (initiator, key_share) = Initiator::new(&hello, &reply_bytes, their_key)?;
+    stream.write_frame(&key_share)?;
+    initiator.finish(&stream.read_frame()?, their_key)
+}
+
+// Runs the handshake from the listening side, with our own private key
+pub fn respond<S: Read + Write>(
+    stream: &mut FramedStream<S>,
+    our_key: &Rsa<Private>,
+) -> Result<Session, ProtocolError> {
+    let (responder, reply) = Responder::new(&stream.read_frame()?)?;
+    stream.write_frame(&reply)?;
+    responder.accepted()?;
+    let (session, key_share) = responder.finish(&stream.read_frame()?, our_key)?;
+    stream.write_frame(&key_share)?;
+    Ok(session)
+}
+
+// What the initiator keeps to work out the secret once the responder has answered
+enum PendingSecret {
+    X25519(EphemeralKey),
+    RsaOaep([u8; 32]),
+}
+
+// The initiator part way through the handshake, waiting for the responder's key share. The steps
+// are kept apart from the I/O so the blocking and async handshakes share them.
+pub(crate) struct Initiator {
+    hello: Vec<u8>,
+    reply: Vec<u8>,
+    version: u16,
+    features: u32,
+    suite: CipherSuite,
+    pending: PendingSecret,
+    key_share: Vec<u8>,
+}
+
+impl Initiator {
+    // Once the other end has answered our hello, along with our key share to send it
+    pub fn new(
+        hello: &[u8],
+        reply_bytes: &[u8],
+        their_key: &Rsa<Public>,
+    ) -> Result<(Self, Vec<u8>), ProtocolError> {
+        let (version, features, suite) = match HelloReply::from_bytes(reply_bytes)? {
+            HelloReply::Accept {
+                version,
+                features,
+                suite,
+            } => (version, features, suite),
+            HelloReply::Reject { reason } => return Err(ProtocolError::Rejected(reason)),
+        };
+        // Never trust the other end to have chosen something we can't speak
+        let offered: bool = Hello::from_bytes(hello)?.suites.contains(&suite);
+        let suite: Option<CipherSuite> = CipherSuite::from_id(suite).filter(|_| offered);
+        let (true, Some(suite)) = (
+            (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+                && features & !SUPPORTED_FEATURES == 0,
+            suite,
+        ) else {
+            return Err(ProtocolError::Malformed(
+                "the other end chose a version, features or suite we did not offer".to_string(),
+            ));
+        };
+        let (pending, key_share) = match suite.key_exchange {
+            KeyExchange::X25519 => {
+                let ephemeral: EphemeralKey = EphemeralKey::generate()?;
+                let key_share: Vec<u8> = ephemeral.key_share()?;
+                (PendingSecret::X25519(ephemeral), key_share)
+            }
+            KeyExchange::RsaOaep => {
+                let mut secret: [u8; 32] = [0; 32];
+                rand_bytes(&mut secret)?;
+                let key_share: Vec<u8> = encrypt_rsa(&secret, their_key)?;
+                (PendingSecret::RsaOaep(secret), key_share)
+            }
+        };
+        Ok((
+            Initiator {
+                hello: hello.to_vec(),
+                reply: reply_bytes.to_vec(),
+                version,
+                features,
+                suite,
+                pending,
+                key_share: key_share.clone(),
+            },
+            key_share,
+        ))
+    }
+
+    // Checks the responder's signature and confirmation, and agrees on the session
+    pub fn finish(
+        self,
+        their_frame: &[u8],
+        their_key: &Rsa<Public>,
+    ) -> Result<Session, ProtocolError> {
+        // The nonce counts as part of their share, for the transcript
+        let share_len: usize = match self.pending {
+            PendingSecret::X25519(_) => NONCE_LEN + KEY_SHARE_LEN,
+            PendingSecret::RsaOaep(_) => NONCE_LEN,
+        };
+        if their_frame.len() <= share_len + CONFIRMATION_LEN {
+            return Err(ProtocolError::Malformed(
+                "key share is missing its confirmation or signature".to_string(),
+            ));
+        }
+        let (their_share, rest) = their_frame.split_at(share_len);
+        let (confirmation, signature) = rest.split_at(CONFIRMATION_LEN);
+        let transcript: [u8; 32] =
+            transcript_hash(&self.hello, &self.reply, &self.key_share, their_share);
+        verify_rsa(&transcript, signature, their_key)
+            .map_err(|_| ProtocolError::KeyNotConfirmed)?;
+        let secret: [u8; 32] = match self.pending {
+            PendingSecret::X25519(ephemeral) => ephemeral.agree(&their_share[NONCE_LEN..])?,
+            PendingSecret::RsaOaep(secret) => secret,
+        };
+        if !memcmp::eq(&key_confirmation(&secret, &transcript)?, confirmation) {
+            return Err(ProtocolError::KeyNotConfirmed);
+        }
+        Session::derive(
+            &secret,
+            &transcript,
+            self.version,
+            self.features,
+            self.suite,
+            Role::Initiator,
+        )
+    }
+}
+
+// The responder part way through the handshake, having answered the initiator's hello and
+// waiting for its key share. Like Initiator, this is the handshake without the I/O.
+pub(crate) struct Responder {
+    hello: Vec<u8>,
+    reply: HelloReply,
+    reply_bytes: Vec<u8>,
+}
+
+impl Responder {
+    // Answers the initiator's hello, along with the reply to send it
+    pub fn new(hello_bytes: &[u8]) -> Result<(Self, Vec<u8>), ProtocolError> {
+        let reply: HelloReply =
+            HelloReply::answer(&Hello::ours(), &Hello::from_bytes(hello_bytes)?);
+        let reply_bytes: Vec<u8> = reply.as_bytes();
+        Ok((
+            Responder {
+                hello: hello_bytes.to_vec(),
+                reply,
+                reply_bytes: reply_bytes.clone(),
+            },
+            reply_bytes,
+        ))
+    }
+
+    // The reply is sent even when it turns the initiator down, after which this ends the handshake
+    // rather than waiting for a key share which will never come
+    pub fn accepted(&self) -> Result<(), ProtocolError> {
+        match &self.reply {
+            HelloReply::Accept { .. } => Ok(()),
+            HelloReply::Reject { reason } => Err(ProtocolError::Rejected(reason.clone())),
+        }
+    }
+
+    // Once the initiator's key share has arrived: the session, and our signed key share to send
+    // back
+    pub fn finish(
+        self,
+        their_share: &[u8],
+        our_key: &Rsa<Private>,
+    ) -> Result<(Session, Vec<u8>), ProtocolError> {
+        let (version, features, suite) = match self.reply {
+            HelloReply::Accept {
+                version,
+                features,
+                suite,
+            } => (version, features, suite),
+            HelloReply::Reject { reason } => return Err(ProtocolError::Rejected(reason)),
+        };
+        let suite: CipherSuite = CipherSuite::from_id(suite)
+            .ok_or_else(|| ProtocolError::Malformed(format!("unknown cipher suite {}", suite)))?;
+        let mut key_share: Vec<u8> = vec![0; NONCE_LEN];
+        rand_bytes(&mut key_share)?;
+        let secret: [u8; 32] = match suite.key_exchange {
+            KeyExchange::X25519 => {
+                let ephemeral: EphemeralKey = EphemeralKey::generate()?;
+                key_share.extend_from_slice(&ephemeral.key_share()?);
+                ephemeral.agree(their_share)?
+            }
+            // Every way this can fail looks the same from outside
+            KeyExchange::RsaOaep => decrypt_rsa(their_share, our_key)?
+                .try_into()
+                .map_err(|_| ProtocolError::AuthenticationFailed)?,
+        };
+        let transcript: [u8; 32] =
+            transcript_hash(&self.hello, &self.reply_bytes, their_share, &key_share);
+        key_share.extend_from_slice(&key_confirmation(&secret, &transcript)?);
+        key_share.extend_from_slice(&sign_rsa(&transcript, our_key)?);
+        let session: Session = Session::derive(
+            &secret,
+            &transcript,
+            version,
+            features,
+            suite,
+            Role::Responder,
+        )?;
+        Ok((session, key_share))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    // The responder's session and key share, having answered the given hello
+    fn respond_to(
+        hello: &[u8],
+        key_share: &[u8],
+        our_key: &Rsa<Private>,
+    ) -> Result<(Session, Vec<u8>), ProtocolError> {
+        let (responder, _) = Responder::new(hello)?;
+        responder.finish(key_share, our_key)
+    }
+
+    #[test]
+    fn both_ends_agree_on_the_session() {
+        let private_key: Rsa<Private> = Rsa::generate(2048).unwrap();
+        let public_key: Rsa<Public> =
+            Rsa::public_key_from_pem(&private_key.public_key_to_pem().unwrap()).unwrap();
+        let (initiator, responder) = UnixStream::pair().unwrap();
+        let responder_thread = thread::spawn(move || {
+            respond(&mut FramedStream::new(responder), &private_key).unwrap()
+        });
+        let initiated: Session = initiate(&mut FramedStream::new(initiator), &public_key).unwrap();
+        let responded: Session = responder_thread.join().unwrap();
+        assert_eq!(initiated.send_key, responded.receive_key);
+        assert_eq!(initiated.receive_key, responded.send_key);
+        assert_eq!(initiated.pair_secret, responded.pair_secret);
+        // Each direction, and the pair secret, has a key of its own
+        assert_ne!(initiated.send_key, initiated.receive_key);
+        assert_ne!(initiated.pair_secret, initiated.send_key);
+        assert_ne!(initiated.pair_secret, initiated.receive_key);
+        // Rekeying gives both ends the same new key
+        assert_eq!(
+            next_key(&initiated.send_key).unwrap(),
+            next_key(&responded.receive_key).unwrap()
+        );
+        assert_ne!(next_key(&initiated.send_key).unwrap(), initiated.send_key);
+        assert_eq!(initiated.version, PROTOCOL_VERSION);
+        assert_eq!(responded.version, PROTOCOL_VERSION);
+        assert_eq!(initiated.role, responded.role.other());
+    }
+
+    #[test]
+    fn impostors_and_altered_hellos_are_caught() {
+        let private_key: Rsa<Private> = Rsa::generate(2048).unwrap();
+        let public_key: Rsa<Public> =
+            Rsa::public_key_from_pem(&private_key.public_key_to_pem().unwrap()).unwrap();
+        let impostor_key: Rsa<Private> = Rsa::generate(2048).unwrap();
+        let hello: Vec<u8> = Hello::ours().as_bytes();
+        let reply: Vec<u8> = HelloReply::answer(&Hello::ours(), &Hello::ours()).as_bytes();
+        // Answered by someone without the expected key
+        let (initiator, key_share) = Initiator::new(&hello, &reply, &public_key).unwrap();
+        let (_, answer) = respond_to(&hello, &key_share, &impostor_key).unwrap();
+        assert!(matches!(
+            initiator.finish(&answer, &public_key),
+            Err(ProtocolError::KeyNotConfirmed)
+        ));
+        // The hello the responder saw was changed on the way, e.g. to strip out features
+        let (initiator, key_share) = Initiator::new(&hello, &reply, &public_key).unwrap();
+        let altered: Vec<u8> = Hello {
+            features: 0,
+            ..Hello::ours()
+        }
+        .as_bytes();
+        let (_, answer) = respond_to(&altered, &key_share, &private_key).unwrap();
+        assert!(matches!(
+            initiator.finish(&answer, &public_key),
+            Err(ProtocolError::KeyNotConfirmed)
+        ));
+        // Signed by the right key, but without having arrived at the same secret
+        let (initiator, key_share) = Initiator::new(&hello, &reply, &public_key).unwrap();
+        let (_, mut answer) = respond_to(&hello, &key_share, &private_key).unwrap();
+        answer[NONCE_LEN + KEY_SHARE_LEN] ^= 1;
+        assert!(matches!(
+            initiator.finish(&answer, &public_key),
+            Err(ProtocolError::KeyNotConfirmed)
+        ));
+        // Nor can it be left out
+        let (initiator, key_share) = Initiator::new(&hello, &reply, &public_key).unwrap();
+        let (_, answer) = respond_to(&hello, &key_share, &private_key).unwrap();
+        assert!(matches!(
+            initiator.finish(
+                &answer[..NONCE_LEN + KEY_SHARE_LEN + CONFIRMATION_LEN],
+                &public_key
+            ),
+            Err(ProtocolError::Malformed(_))
+        ));
+        // Untouched, both ends agree, and a new handshake gives a new key
+        let (initiator, key_share) = Initiator::new(&hello, &reply, &public_key).unwrap();
+        let (responded, answer) = respond_to(&hello, &key_share, &private_key).unwrap();
+        let initiated: Session = initiator.finish(&answer, &public_key).unwrap();
+        assert_eq!(initiated.send_key, responded.receive_key);
+        let (initiator, key_share) = Initiator::new(&hello, &reply, &public_key).unwrap();
+        let (_, answer) = respond_to(&hello, &key_share, &private_key).unwrap();
+        assert_ne!(
+            initiator.finish(&answer, &public_key).unwrap().pair_secret,
+            initiated.pair_secret
+        );
+    }
+
+    #[test]
+    fn every_suite_agrees_on_a_session() {
+        let private_key: Rsa<Private> = Rsa::generate(2048).unwrap();
+        let public_key: Rsa<Public> =
+            Rsa::public_key_from_pem(&private_key.public_key_to_pem().unwrap()).unwrap();
+        for suite in crate::suites::CIPHER_SUITES {
+            let hello: Vec<u8> = Hello {
+                suites: vec![suite.id],
+                ..Hello::ours()
+            }
+            .as_bytes();
+            let reply: Vec<u8> =
+                HelloReply::answer(&Hello::ours(), &Hello::from_bytes(&hello).unwrap()).as_bytes();
+            let (initiator, key_share) = Initiator::new(&hello, &reply, &public_key).unwrap();
+            let (responded, answer) = respond_to(&hello, &key_share, &private_key).unwrap();
+            let initiated: Session = initiator.finish(&answer, &public_key).unwrap();
+            assert_eq!(initiated.suite, suite);
+            assert_eq!(responded.suite, suite);
+            assert_eq!(initiated.send_key, responded.receive_key);
+            assert_eq!(initiated.pair_secret, responded.pair_secret);
+        }
+        // A suite we didn't offer is refused, even one we know
+        let hello: Vec<u8> = Hello {
+            suites: vec![1],
+            ..Hello::ours()
+        }
+        .as_bytes();
+        let reply: Vec<u8> = HelloReply::Accept {
+            version: PROTOCOL_VERSION,
+            features: 0,
+            suite: 3,
+        }
+        .as_bytes();
+        assert!(matches!(
+            Initiator::new(&hello, &reply, &public_key),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn replayed_initiators_get_fresh_keys() {
+        let private_key: Rsa<Private> = Rsa::generate(2048).unwrap();
+        let public_key: Rsa<Public> =
+            Rsa::public_key_from_pem(&private_key.public_key_to_pem().unwrap()).unwrap();
+        let rsa_oaep: CipherSuite = crate::suites::CIPHER_SUITES
+            .into_iter()
+            .find(|suite| suite.key_exchange == KeyExchange::RsaOaep)
+            .unwrap();
+        let hello: Vec<u8> = Hello {
+            suites: vec![rsa_oaep.id],
+            ..Hello::ours()
+        }
+        .as_bytes();
+        let reply: Vec<u8> =
+            HelloReply::answer(&Hello::ours(), &Hello::from_bytes(&hello).unwrap()).as_bytes();
+        let (initiator, key_share) = Initiator::new(&hello, &reply, &public_key).unwrap();
+        // The same recorded flight, answered twice
+        let (first, answer) = respond_to(&hello, &key_share, &private_key).unwrap();
+        let (second, _) = respond_to(&hello, &key_share, &private_key).unwrap();
+        assert_ne!(first.send_key, second.send_key);
+        assert_ne!(first.receive_key, second.receive_key);
+        assert_ne!(first.pair_secret, second.pair_secret);
+        // While the initiator which really sent it agrees with the answer it got
+        let initiated: Session = initiator.finish(&answer, &public_key).unwrap();
+        assert_eq!(initiated.receive_key, first.send_key);
+    }
+
+    #[test]
+    fn highest_common_version_is_chosen() {
+        let ours = Hello {
+            min_version: 1,
+            max_version: 3,
+            features: 0b101,
+            suites: vec![1, 2, 3],
+        };
+        let theirs = Hello {
+            min_version: 2,
+            max_version: 5,
+            features: 0b110,
+            suites: vec![9, 3, 1],
+        };
+        // Their favourite suite of the ones we know
+        assert_eq!(
+            HelloReply::answer(&ours, &theirs),
+            HelloReply::Accept {
+                version: 3,
+                features: 0b100,
+                suite: 3
+            }
+        );
+        assert_eq!(Hello::from_bytes(&theirs.as_bytes()).unwrap(), theirs);
+        // Too many suites to count are cut down to the most preferred
+        let crowded: Hello = Hello {
+            suites: (0..300).collect(),
+            ..theirs.clone()
+        };
+        let decoded: Hello = Hello::from_bytes(&crowded.as_bytes()).unwrap();
+        assert_eq!(decoded.suites, crowded.suites[..255]);
+        let reply = HelloReply::answer(&ours, &theirs);
+        assert_eq!(HelloReply::from_bytes(&reply.as_bytes()).unwrap(), reply);
+    }
+
+    #[test]
+    fn incompatible_versions_are_rejected() {
+        let ours = Hello {
+            min_version: 4,
+            max_version: 4,
+            features: 0,
+            suites: vec![1],
+        };
+        let theirs = Hello {
+            min_version: 1,
+            max_version: 3,
+            features: 0,
+            suites: vec![1],
+        };
+        let reply = HelloReply::answer(&ours, &theirs);
+        assert!(matches!(reply, HelloReply::Reject { .. }));
+        assert_eq!(HelloReply::from_bytes(&reply.as_bytes()).unwrap(), reply);
+        // The responder sends the rejection, then goes no further
+        let (responder, reply_bytes) = Responder::new(&theirs.as_bytes()).unwrap();
+        assert!(matches!(
+            HelloReply::from_bytes(&reply_bytes).unwrap(),
+            HelloReply::Reject { .. }
+        ));
+        assert!(matches!(
+            responder.accepted(),
+            Err(ProtocolError::Rejected(_))
+        ));
+        assert!(Hello::from_bytes(&[0; 256]).is_err());
+        // Nor is there a connection without a suite in common
+        let theirs = Hello {
+            min_version: 4,
+            max_version: 4,
+            features: 0,
+            suites: vec![2],
+        };
+        assert!(matches!(
+            HelloReply::answer(&ours, &theirs),
+            HelloReply::Reject { .. }
+        ));
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Keeping track of whether the other end of a link is still there.
+// Anything received counts as a sign of life. Once a link has been quiet for the interval a Ping
+// is sent, which the other end answers with a Pong, and once it has been quiet for the timeout the
+// link is treated as dead.
+
+use crate::{Message, MessageType};
+use std::env;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(20);
+// Both in milliseconds, overriding the defaults above
+pub const HEARTBEAT_INTERVAL_VARIABLE: &str = "CRUSH_HEARTBEAT_INTERVAL_MS";
+pub const HEARTBEAT_TIMEOUT_VARIABLE: &str = "CRUSH_HEARTBEAT_TIMEOUT_MS";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: DEFAULT_HEARTBEAT_INTERVAL,
+            timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    // The defaults, with anything set in the environment taking their place
+    pub fn from_env() -> Self {
+        let millis = |variable: &str| -> Option<Duration> {
+            env::var(variable)
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .map(Duration::from_millis)
+        };
+        let default: HeartbeatConfig = HeartbeatConfig::default();
+        HeartbeatConfig {
+            interval: millis(HEARTBEAT_INTERVAL_VARIABLE).unwrap_or(default.interval),
+            timeout: millis(HEARTBEAT_TIMEOUT_VARIABLE).unwrap_or(default.timeout),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Heartbeat {
+    config: HeartbeatConfig,
+    last_received: Instant,
+    last_ping: Option<Instant>,
+    pings_sent: u64,
+}
+
+impl Heartbeat {
+    // Everything takes the current time from the caller, so the clock can be stepped in tests
+    pub fn new(config: HeartbeatConfig, now: Instant) -> Self {
+        Heartbeat {
+            config,
+            last_received: now,
+            last_ping: None,
+            pings_sent: 0,
+        }
+    }
+
+    // Called for every message received on the link
+    pub fn received(&mut self, now: Instant) {
+        self.last_received = now;
+    }
+
+    // Whether the link has been quiet long enough to ask, without asking again every time
+    pub fn ping_due(&self, now: Instant) -> bool {
+        now.duration_since(self.last_received) >= self.config.interval
+            && self
+                .last_ping
+                .is_none_or(|sent| now.duration_since(sent) >= self.config.interval)
+    }
+
+    // Makes the next Ping to send, counting it as sent
+    pub fn ping(&mut self, now: Instant) -> Message {
+        self.last_ping = Some(now);
+        self.pings_sent += 1;
+        Message::new(self.pings_sent.to_be_bytes().to_vec(), MessageType::Ping)
+    }
+
+    pub fn timed_out(&self, now: Instant) -> bool {
+        now.duration_since(self.last_received) >= self.config.timeout
+    }
+
+    // The answer to a Ping from the other end
+    pub fn pong(ping: &Message) -> Message {
+        Message::new(ping.content.clone(), MessageType::Pong)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quiet_links_are_pinged_then_timed_out() {
+        let start: Instant = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        let mut heartbeat: Heartbeat = Heartbeat::new(
+            HeartbeatConfig {
+                interval: Duration::from_millis(30),
+                timeout: Duration::from_millis(90),
+            },
+            start,
+        );
+        assert!(!heartbeat.ping_due(at(29)));
+        assert!(heartbeat.ping_due(at(30)));
+        let ping: Message = heartbeat.ping(at(30));
+        assert_eq!(ping.message_type, MessageType::Ping);
+        // Only one Ping per interval
+        assert!(!heartbeat.ping_due(at(59)));
+        assert!(heartbeat.ping_due(at(60)));
+        let pong: Message = Heartbeat::pong(&ping);
+        assert_eq!(pong.message_type, MessageType::Pong);
+        assert_eq!(pong.content, ping.content);
+        // The Pong coming back keeps the link alive
+        heartbeat.received(at(40));
+        assert!(!heartbeat.ping_due(at(60)));
+        assert!(!heartbeat.timed_out(at(129)));
+        assert!(heartbeat.timed_out(at(130)));
+    }
+}
@@ -0,0 +1,115 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Ephemeral X25519 key agreement and HKDF, used by the handshake to agree on session keys which
+// can't be recovered later from either end's long-term key.
+
+use crate::ProtocolError;
+use openssl::derive::Deriver;
+use openssl::md::Md;
+use openssl::pkey::{Id, PKey, Private, Public};
+use openssl::pkey_ctx::PkeyCtx;
+
+pub const KEY_SHARE_LEN: usize = 32;
+
+// A key pair used for a single handshake, then thrown away
+pub struct EphemeralKey(PKey<Private>);
+
+impl EphemeralKey {
+    pub fn generate() -> Result<Self, ProtocolError> {
+        Ok(EphemeralKey(PKey::generate_x25519()?))
+    }
+
+    // What the other end needs to agree on a secret with us
+    pub fn key_share(&self) -> Result<Vec<u8>, ProtocolError> {
+        Ok(self.0.raw_public_key()?)
+    }
+
+    // Takes the key, so it can't be used for anything after this exchange
+    pub fn agree(self, their_share: &[u8]) -> Result<[u8; 32], ProtocolError> {
+        if their_share.len() != KEY_SHARE_LEN {
+            return Err(ProtocolError::Malformed(format!(
+                "key share should be {} bytes",
+                KEY_SHARE_LEN
+            )));
+        }
+        let theirs: PKey<Public> = PKey::public_key_from_raw_bytes(their_share, Id::X25519)
+            .map_err(|_| ProtocolError::Malformed("invalid key share".to_string()))?;
+        let mut deriver: Deriver = Deriver::new(&self.0)?;
+        deriver.set_peer(&theirs)?;
+        let mut secret: [u8; 32] = [0; 32];
+        // OpenSSL refuses shares which would give an all-zero secret
+        deriver
+            .derive(&mut secret)
+            .map_err(|_| ProtocolError::Malformed("invalid key share".to_string()))?;
+        Ok(secret)
+    }
+}
+
+// HKDF with SHA-256, filling output with key material for the purpose named by info
+pub fn hkdf_sha256(
+    secret: &[u8],
+    salt: &[u8],
+    info: &[u8],
+    output: &mut [u8],
+) -> Result<(), ProtocolError> {
+    let mut context: PkeyCtx<()> = PkeyCtx::new_id(Id::HKDF)?;
+    context.derive_init()?;
+    context.set_hkdf_md(Md::sha256())?;
+    context.set_hkdf_key(secret)?;
+    context.set_hkdf_salt(salt)?;
+    context.add_hkdf_info(info)?;
+    context.derive(Some(output))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_ends_agree_and_bad_shares_are_refused() {
+        let ours: EphemeralKey = EphemeralKey::generate().unwrap();
+        let theirs: EphemeralKey = EphemeralKey::generate().unwrap();
+        let our_share: Vec<u8> = ours.key_share().unwrap();
+        let their_share: Vec<u8> = theirs.key_share().unwrap();
+        assert_eq!(our_share.len(), KEY_SHARE_LEN);
+        assert_eq!(
+            ours.agree(&their_share).unwrap(),
+            theirs.agree(&our_share).unwrap()
+        );
+        // A low order point, which would give an all-zero secret
+        let zero: EphemeralKey = EphemeralKey::generate().unwrap();
+        assert!(zero.agree(&[0; KEY_SHARE_LEN]).is_err());
+        let short: EphemeralKey = EphemeralKey::generate().unwrap();
+        assert!(short.agree(&[1; 16]).is_err());
+    }
+
+    #[test]
+    fn hkdf_matches_rfc_5869() {
+        // Test case 1 from RFC 5869
+        let secret: [u8; 22] = [0x0b; 22];
+        let salt: Vec<u8> = (0x00..=0x0c).collect();
+        let info: Vec<u8> = (0xf0..=0xf9).collect();
+        let mut output: [u8; 42] = [0; 42];
+        hkdf_sha256(&secret, &salt, &info, &mut output).unwrap();
+        let expected: [u8; 42] = [
+            0x3c, 0xb2, 0x5f, 0x25, 0xfa, 0xac, 0xd5, 0x7a, 0x90, 0x43, 0x4f, 0x64, 0xd0, 0x36,
+            0x2f, 0x2a, 0x2d, 0x2d, 0x0a, 0x90, 0xcf, 0x1a, 0x5a, 0x4c, 0x5d, 0xb0, 0x2d, 0x56,
+            0xec, 0xc4, 0xc5, 0xbf, 0x34, 0x00, 0x72, 0x08, 0xd5, 0xb8, 0x87, 0x18, 0x58, 0x65,
+        ];
+        assert_eq!(output, expected);
+    }
+}
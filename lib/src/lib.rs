@@ -13,17 +13,55 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use core::panic;
-use openssl::pkey::{Private, Public};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private, Public};
 use openssl::rand::rand_bytes;
 use openssl::rsa::{Padding, Rsa};
 use openssl::sha::sha256;
+use openssl::sign::{RsaPssSaltlen, Signer, Verifier};
 use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
 use std::fs::File;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::io::Read;
 
-#[derive(Debug, Clone)]
+#[cfg(feature = "async")]
+mod async_channel;
+mod channel;
+mod compression;
+mod error;
+mod framing;
+pub mod handshake;
+mod heartbeat;
+mod kex;
+mod padding;
+pub mod payloads;
+mod record;
+pub mod rpc;
+mod sequence;
+pub mod suites;
+pub mod tls;
+pub mod transfer;
+pub mod transport;
+pub mod websocket;
+#[cfg(feature = "async")]
+pub use async_channel::AsyncSecureChannel;
+pub use channel::SecureChannel;
+pub use error::ProtocolError;
+pub use framing::{
+    FramedStream, PEER_LINK_MAX_FRAME_LEN, PEER_LINK_MAX_TRANSFER_LEN, SERVER_LINK_MAX_FRAME_LEN,
+    SERVER_LINK_MAX_TRANSFER_LEN,
+};
+pub use heartbeat::{Heartbeat, HeartbeatConfig};
+pub use padding::{PaddingPolicy, DEFAULT_PADDING_BUCKETS};
+pub use record::RekeyPolicy;
+pub use sequence::{Role, SequenceNumbers};
+pub use transport::{Listener, MemoryConnector, MemoryListener, MemoryPipe, Transport};
+
+// AES-GCM adds 12 bytes of IV and 16 bytes of tag to every plaintext, as does ChaCha20-Poly1305
+const AES_OVERHEAD: usize = 28;
+// Length, type and ID
+const MESSAGE_HEADER_LEN: usize = 17;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MessageType {
     NORMAL,
     DEBUG,
@@ -33,6 +71,21 @@ pub enum MessageType {
     InformPublicKey,
     InformAddress,
     Secret,
+    // Sent when a link has been quiet, answered with a Pong carrying the same content
+    Ping,
+    Pong,
+    // The last message on a connection, saying why it is being closed
+    Goodbye,
+    // Confirms a message sent with an ID has arrived
+    Ack,
+    // A call wrapping another message, answered by a Reply with the same call ID
+    Request,
+    Reply,
+    // A message too large for one frame, sent in chunks (see transfer.rs)
+    TransferStart,
+    TransferChunk,
+    // The last record sealed with the sender's current key, before it moves on to the next one
+    KeyUpdate,
 }
 
 impl MessageType {
@@ -46,20 +99,43 @@ impl MessageType {
             Self::InformPublicKey => [5],
             Self::InformAddress => [6],
             Self::Secret => [7],
+            Self::Ping => [8],
+            Self::Pong => [9],
+            Self::Goodbye => [10],
+            Self::Ack => [11],
+            Self::Request => [12],
+            Self::Reply => [13],
+            // 14 to 17 were the logical channel messages, and are left unused
+            Self::TransferStart => [18],
+            Self::TransferChunk => [19],
+            Self::KeyUpdate => [20],
         }
     }
+}
 
-    pub fn from_bytes(bytes: [u8; 1]) -> Self {
-        match bytes {
-            [0] => Self::NORMAL,
-            [1] => Self::DEBUG,
-            [2] => Self::RemovePeer,
-            [3] => Self::AddPeer,
-            [4] => Self::RequestPublicKey,
-            [5] => Self::InformPublicKey,
-            [6] => Self::InformAddress,
-            [7] => Self::Secret,
-            _ => panic!("Unexpected bytes in MessageType reading"),
+impl TryFrom<u8> for MessageType {
+    type Error = ProtocolError;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        match byte {
+            0 => Ok(Self::NORMAL),
+            1 => Ok(Self::DEBUG),
+            2 => Ok(Self::RemovePeer),
+            3 => Ok(Self::AddPeer),
+            4 => Ok(Self::RequestPublicKey),
+            5 => Ok(Self::InformPublicKey),
+            6 => Ok(Self::InformAddress),
+            7 => Ok(Self::Secret),
+            8 => Ok(Self::Ping),
+            9 => Ok(Self::Pong),
+            10 => Ok(Self::Goodbye),
+            11 => Ok(Self::Ack),
+            12 => Ok(Self::Request),
+            13 => Ok(Self::Reply),
+            18 => Ok(Self::TransferStart),
+            19 => Ok(Self::TransferChunk),
+            20 => Ok(Self::KeyUpdate),
+            _ => Err(ProtocolError::UnknownMessageType(byte)),
         }
     }
 }
@@ -83,36 +159,44 @@ impl Message {
 struct MessageHeader {
     message_len: usize,
     message_type: MessageType,
+    // Non-zero for messages which the other end should acknowledge
+    message_id: u64,
 }
 
 impl MessageHeader {
-    fn new(message: &[u8], message_type: MessageType) -> MessageHeader {
+    fn new(message: &[u8], message_type: MessageType, message_id: u64) -> MessageHeader {
         MessageHeader {
             message_len: message.len(),
             message_type,
+            message_id,
         }
     }
 
-    fn as_bytes(&self) -> [u8; 9] {
-        let mut bytes: [u8; 9] = [0; 9];
-        let length_bytes = self.message_len.to_be_bytes();
-        for i in 0..8 {
-            bytes[i] = length_bytes[i];
-        }
+    fn as_bytes(&self) -> [u8; MESSAGE_HEADER_LEN] {
+        let mut bytes: [u8; MESSAGE_HEADER_LEN] = [0; MESSAGE_HEADER_LEN];
+        bytes[..8].copy_from_slice(&self.message_len.to_be_bytes());
         bytes[8] = self.message_type.as_bytes()[0];
-        return bytes;
+        bytes[9..].copy_from_slice(&self.message_id.to_be_bytes());
+        bytes
     }
 
-    fn from_bytes(bytes: &[u8]) -> Self {
-        let mut length_bytes: [u8; 8] = [0; 8];
-        let type_byte: [u8; 1] = [bytes[8]];
-        for i in 0..8 {
-            length_bytes[i] = bytes[i];
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        if bytes.len() != MESSAGE_HEADER_LEN {
+            return Err(ProtocolError::Malformed(format!(
+                "message header is {} bytes, expected {}",
+                bytes.len(),
+                MESSAGE_HEADER_LEN
+            )));
         }
-        MessageHeader {
+        let mut length_bytes: [u8; 8] = [0; 8];
+        length_bytes.copy_from_slice(&bytes[..8]);
+        let mut id_bytes: [u8; 8] = [0; 8];
+        id_bytes.copy_from_slice(&bytes[9..]);
+        Ok(MessageHeader {
             message_len: usize::from_be_bytes(length_bytes),
-            message_type: MessageType::from_bytes(type_byte),
-        }
+            message_type: MessageType::try_from(bytes[8])?,
+            message_id: u64::from_be_bytes(id_bytes),
+        })
     }
 }
 
@@ -120,178 +204,162 @@ pub fn add(left: usize, right: usize) -> usize {
     left + right
 }
 
-pub fn get_rsa_public_key(filepath: &str) -> Rsa<Public> {
-    let mut file: File = File::open(filepath).expect("The filepath should be a valid path");
+pub fn get_rsa_public_key(filepath: &str) -> Result<Rsa<Public>, ProtocolError> {
+    let mut file: File = File::open(filepath)?;
     let mut file_contents: String = String::new();
-    file.read_to_string(&mut file_contents).unwrap();
-    Rsa::public_key_from_pem(file_contents.as_bytes())
-        .expect("The file should be a valid PEM-encoded RSA public key")
+    file.read_to_string(&mut file_contents)?;
+    Rsa::public_key_from_pem(file_contents.as_bytes()).map_err(|_| {
+        ProtocolError::Malformed(format!("{} is not a PEM-encoded RSA public key", filepath))
+    })
 }
 
-pub fn get_rsa_private_key(filepath: &str) -> Rsa<Private> {
-    let mut file: File = File::open(filepath).expect("The filepath should be a valid path");
+pub fn get_rsa_private_key(filepath: &str) -> Result<Rsa<Private>, ProtocolError> {
+    let mut file: File = File::open(filepath)?;
     let mut file_contents: String = String::new();
-    file.read_to_string(&mut file_contents).unwrap();
-    Rsa::private_key_from_pem(file_contents.as_bytes())
-        .expect("The file should be a valid PEM-encoded RSA private key")
+    file.read_to_string(&mut file_contents)?;
+    Rsa::private_key_from_pem(file_contents.as_bytes()).map_err(|_| {
+        ProtocolError::Malformed(format!("{} is not a PEM-encoded RSA private key", filepath))
+    })
 }
 
-pub fn encrypt_rsa(data: &[u8], key: &Rsa<Public>) -> Vec<u8> {
-    let mut result: Vec<u8> = Vec::new();
-    result.resize(key.size() as usize, 0);
-    key.public_encrypt(data, result.as_mut_slice(), Padding::PKCS1)
-        .unwrap();
-    result
+pub fn encrypt_rsa(data: &[u8], key: &Rsa<Public>) -> Result<Vec<u8>, ProtocolError> {
+    let mut result: Vec<u8> = vec![0; key.size() as usize];
+    key.public_encrypt(data, result.as_mut_slice(), Padding::PKCS1_OAEP)?;
+    Ok(result)
 }
 
-pub fn decrypt_rsa(data: &[u8], key: &Rsa<Private>) -> Vec<u8> {
-    let mut result: Vec<u8> = Vec::new();
-    result.resize(key.size() as usize, 0);
-    key.private_decrypt(data, result.as_mut_slice(), Padding::PKCS1)
-        .unwrap();
-    result
+pub fn decrypt_rsa(data: &[u8], key: &Rsa<Private>) -> Result<Vec<u8>, ProtocolError> {
+    let mut result: Vec<u8> = vec![0; key.size() as usize];
+    let decrypted_len: usize = key
+        .private_decrypt(data, result.as_mut_slice(), Padding::PKCS1_OAEP)
+        .map_err(|_| ProtocolError::AuthenticationFailed)?;
+    result.truncate(decrypted_len);
+    Ok(result)
 }
 
-// Encrypts with AES, returns 12 bytes of IV, 16 bytes of tag and the remainder is the encrypted ciphertext
-pub fn encrypt_aes(data: &[u8], key: &[u8; 32], tag: &mut [u8; 16]) -> Vec<u8> {
-    let cipher: Cipher = Cipher::aes_256_gcm();
-    let mut iv: [u8; 12] = [0; 12];
-    rand_bytes(&mut iv).unwrap();
-    let mut encrypted: Vec<u8> = Vec::with_capacity(12);
-    let mut ciphertext = encrypt_aead(cipher, key, Some(&iv), &[], data, tag).unwrap();
-    encrypted.append(&mut iv.to_vec());
-    encrypted.append(&mut tag.clone().to_vec());
-    encrypted.append(&mut ciphertext);
-    encrypted
+// Signs with RSA-PSS over SHA-256
+pub fn sign_rsa(data: &[u8], key: &Rsa<Private>) -> Result<Vec<u8>, ProtocolError> {
+    let key: PKey<Private> = PKey::from_rsa(key.clone())?;
+    let mut signer: Signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.set_rsa_padding(Padding::PKCS1_PSS)?;
+    signer.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
 }
 
-pub fn decrypt_aes(data: &[u8], key: &[u8; 32], iv: [u8; 12], tag: &[u8; 16]) -> Option<Vec<u8>> {
-    let cipher: Cipher = Cipher::aes_256_gcm();
-    match decrypt_aead(cipher, key, Some(&iv), &[], data, tag) {
-        Ok(decrypted) => Some(decrypted),
-        Err(_) => None,
+// Checks a signature made by sign_rsa, failing with AuthenticationFailed if it doesn't match
+pub fn verify_rsa(data: &[u8], signature: &[u8], key: &Rsa<Public>) -> Result<(), ProtocolError> {
+    let key: PKey<Public> = PKey::from_rsa(key.clone())?;
+    let mut verifier: Verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+    verifier.set_rsa_padding(Padding::PKCS1_PSS)?;
+    verifier.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+    verifier.update(data)?;
+    match verifier.verify(signature) {
+        Ok(true) => Ok(()),
+        _ => Err(ProtocolError::AuthenticationFailed),
     }
 }
 
-// Given data returned from encrypt_aes, split into its component parts and decrypt it
-pub fn read_and_decrypt_aes(data: &[u8], key: &[u8; 32]) -> Option<Vec<u8>> {
-    let mut iv: [u8; 12] = [0; 12];
-    let mut tag: [u8; 16] = [0; 16];
-    for i in 0..12 {
-        iv[i] = data[i];
-    }
-    for i in 12..28 {
-        tag[i - 12] = data[i];
-    }
-    let ciphertext: &[u8] = data.split_at(28).1;
-    decrypt_aes(ciphertext, key, iv, &tag)
+// Encrypts with AES, returns 12 bytes of IV, 16 bytes of tag and the remainder is the encrypted ciphertext
+pub fn encrypt_aes(
+    data: &[u8],
+    key: &[u8; 32],
+    tag: &mut [u8; 16],
+) -> Result<Vec<u8>, ProtocolError> {
+    encrypt_aes_with_aad(data, &[], key, tag)
 }
 
-// Splits a message into n equal parts
-// Used for chunking large messages into smaller components
-pub fn split_message(message: &[u8], chunk_size: usize) -> Vec<Vec<u8>> {
-    let mut chunked_message: Vec<Vec<u8>> = Vec::new();
-    for i in 0..(message.len() / chunk_size) {
-        chunked_message.push(
-            message
-                .split_at(i * chunk_size)
-                .1
-                .split_at(chunk_size)
-                .0
-                .to_vec(),
-        );
-    }
-    chunked_message.push(
-        message
-            .split_at(message.len() - message.len() % chunk_size)
-            .1
-            .to_vec(),
-    );
-    chunked_message
+// As encrypt_aes, with associated data which is authenticated but not included in the output
+pub fn encrypt_aes_with_aad(
+    data: &[u8],
+    aad: &[u8],
+    key: &[u8; 32],
+    tag: &mut [u8; 16],
+) -> Result<Vec<u8>, ProtocolError> {
+    seal_with(Cipher::aes_256_gcm(), data, aad, key, tag)
 }
 
-// Sends a message of any size to a given tcp stream
-pub fn send_bytes_message(
-    message: &[u8],
-    message_type: MessageType,
-    tcp_stream: &mut TcpStream,
+// Encrypts with any of the AEADs in suites.rs, which all use a 12 byte IV and 16 byte tag
+fn seal_with(
+    cipher: Cipher,
+    data: &[u8],
+    aad: &[u8],
     key: &[u8; 32],
     tag: &mut [u8; 16],
-) -> Result<(), String> {
-    let message_header: MessageHeader = MessageHeader::new(message, message_type);
-    let encrypted_message_header: Vec<u8> = encrypt_aes(&message_header.as_bytes(), key, tag);
-    match tcp_stream.write(encrypted_message_header.as_slice()) {
-        Ok(_) => {}
-        Err(err) => return Err(err.to_string()),
-    };
-    let encrypted_message: Vec<u8> = encrypt_aes(message, key, tag);
-    match tcp_stream.write(encrypted_message.as_slice()) {
-        Ok(_) => {}
-        Err(err) => return Err(err.to_string()),
-    };
-    Ok(())
+) -> Result<Vec<u8>, ProtocolError> {
+    let mut iv: [u8; 12] = [0; 12];
+    rand_bytes(&mut iv)?;
+    let mut encrypted: Vec<u8> = Vec::with_capacity(AES_OVERHEAD + data.len());
+    let mut ciphertext = encrypt_aead(cipher, key, Some(&iv), aad, data, tag)?;
+    encrypted.extend_from_slice(&iv);
+    encrypted.extend_from_slice(tag);
+    encrypted.append(&mut ciphertext);
+    Ok(encrypted)
 }
 
-// Receives a message of any size from a tcp stream
-pub fn receive_bytes_message(tcp_stream: &mut TcpStream, key: &[u8; 32]) -> Option<Vec<u8>> {
-    let mut encrypted_message_header: [u8; 37] = [0; 37];
-    tcp_stream.read(&mut encrypted_message_header).unwrap();
-    let message_header_bytes: Vec<u8> = match read_and_decrypt_aes(&encrypted_message_header, key) {
-        Some(value) => value,
-        None => return None,
-    };
-    let header: MessageHeader = MessageHeader::from_bytes(message_header_bytes.as_slice());
-    println!("{:?}", header);
-    let mut encrypted_message: Vec<u8> = Vec::with_capacity(28 + header.message_len);
-    encrypted_message.resize(28 + header.message_len, 0);
-    tcp_stream.read(encrypted_message.as_mut_slice()).unwrap();
-    let message: Vec<u8> = match read_and_decrypt_aes(encrypted_message.as_slice(), key) {
-        Some(value) => value,
-        None => return None,
-    };
-    Some(message)
+pub fn decrypt_aes(
+    data: &[u8],
+    key: &[u8; 32],
+    iv: [u8; 12],
+    tag: &[u8; 16],
+) -> Result<Vec<u8>, ProtocolError> {
+    decrypt_with(Cipher::aes_256_gcm(), data, &[], key, iv, tag)
 }
 
-pub fn send_message(
-    message: Message,
-    tcp_stream: &mut TcpStream,
+fn decrypt_with(
+    cipher: Cipher,
+    data: &[u8],
+    aad: &[u8],
     key: &[u8; 32],
-    tag: &mut [u8; 16],
-) -> Result<(), String> {
-    send_bytes_message(
-        message.content.as_slice(),
-        message.message_type,
-        tcp_stream,
-        key,
-        tag,
-    )
+    iv: [u8; 12],
+    tag: &[u8; 16],
+) -> Result<Vec<u8>, ProtocolError> {
+    decrypt_aead(cipher, key, Some(&iv), aad, data, tag)
+        .map_err(|_| ProtocolError::AuthenticationFailed)
 }
 
-pub fn receive_message(tcp_stream: &mut TcpStream, key: &[u8; 32]) -> Option<Message> {
-    let mut encrypted_message_header: [u8; 37] = [0; 37];
-    match tcp_stream.read(&mut encrypted_message_header) {
-        Ok(value) => value,
-        Err(_) => return None,
-    };
-    let message_header_bytes: Vec<u8> = match read_and_decrypt_aes(&encrypted_message_header, key) {
-        Some(value) => value,
-        None => return None,
-    };
-    let header: MessageHeader = MessageHeader::from_bytes(message_header_bytes.as_slice());
-    let mut encrypted_message: Vec<u8> = Vec::with_capacity(28 + header.message_len);
-    encrypted_message.resize(28 + header.message_len, 0);
-    tcp_stream.read(encrypted_message.as_mut_slice()).unwrap();
-    let message: Vec<u8> = match read_and_decrypt_aes(encrypted_message.as_slice(), key) {
-        Some(value) => value,
-        None => return None,
-    };
-    match header.message_type {
-        MessageType::DEBUG => {
-            println!("{:?}", String::from_utf8(message.clone()))
-        }
-        _ => {}
+// Given data returned from encrypt_aes, split into its component parts and decrypt it
+pub fn read_and_decrypt_aes(data: &[u8], key: &[u8; 32]) -> Result<Vec<u8>, ProtocolError> {
+    read_and_decrypt_aes_with_aad(data, &[], key)
+}
+
+// Given data returned from encrypt_aes_with_aad and the same associated data, decrypt it
+pub fn read_and_decrypt_aes_with_aad(
+    data: &[u8],
+    aad: &[u8],
+    key: &[u8; 32],
+) -> Result<Vec<u8>, ProtocolError> {
+    open_with(Cipher::aes_256_gcm(), data, aad, key)
+}
+
+fn open_with(
+    cipher: Cipher,
+    data: &[u8],
+    aad: &[u8],
+    key: &[u8; 32],
+) -> Result<Vec<u8>, ProtocolError> {
+    if data.len() < AES_OVERHEAD {
+        return Err(ProtocolError::Malformed(format!(
+            "{} bytes is too short to be encrypted",
+            data.len()
+        )));
     }
-    Some(Message::new(message, header.message_type))
+    let mut iv: [u8; 12] = [0; 12];
+    let mut tag: [u8; 16] = [0; 16];
+    iv.copy_from_slice(&data[..12]);
+    tag.copy_from_slice(&data[12..AES_OVERHEAD]);
+    let ciphertext: &[u8] = data.split_at(AES_OVERHEAD).1;
+    decrypt_with(cipher, ciphertext, aad, key, iv, &tag)
+}
+
+// Splits a message into chunks of chunk_size bytes, the last holding whatever is left over
+// Used for chunking large messages into smaller components. There is always at least one chunk, and
+// a chunk_size of 0 puts the whole message in it.
+pub fn split_message(message: &[u8], chunk_size: usize) -> Vec<Vec<u8>> {
+    if message.is_empty() || chunk_size == 0 {
+        return vec![message.to_vec()];
+    }
+    message.chunks(chunk_size).map(<[u8]>::to_vec).collect()
 }
 
 pub fn hash_string(input: String) -> [u8; 32] {
@@ -307,4 +375,41 @@ mod tests {
         let result = add(2, 2);
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn messages_split_into_whole_chunks() {
+        assert_eq!(
+            split_message(b"abcdef", 3),
+            [b"abc".to_vec(), b"def".to_vec()]
+        );
+        assert_eq!(split_message(b"abcdefg", 3)[2], b"g");
+        assert_eq!(split_message(b"", 3), [Vec::<u8>::new()]);
+        assert_eq!(split_message(b"abc", 0), [b"abc".to_vec()]);
+    }
+
+    #[test]
+    fn unknown_message_types_are_rejected() {
+        assert!(matches!(
+            MessageType::try_from(200),
+            Err(ProtocolError::UnknownMessageType(200))
+        ));
+        assert!(matches!(MessageType::try_from(7), Ok(MessageType::Secret)));
+    }
+
+    #[test]
+    fn tampered_messages_fail_authentication() {
+        let key: [u8; 32] = [7; 32];
+        let mut tag: [u8; 16] = [0; 16];
+        let mut encrypted: Vec<u8> = encrypt_aes(b"secret", &key, &mut tag).unwrap();
+        assert_eq!(read_and_decrypt_aes(&encrypted, &key).unwrap(), b"secret");
+        encrypted[AES_OVERHEAD] ^= 1;
+        assert!(matches!(
+            read_and_decrypt_aes(&encrypted, &key),
+            Err(ProtocolError::AuthenticationFailed)
+        ));
+        assert!(matches!(
+            read_and_decrypt_aes(&encrypted[..10], &key),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
 }
@@ -0,0 +1,133 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Padding records so their length gives away as little as possible about what is in them.
+// On connections which negotiated handshake::FEATURE_PADDING, every record ends with a 0x80 byte
+// and then any number of zeroes, all inside the encryption. The receiver strips back to the 0x80,
+// so how much to pad is up to the sender alone.
+
+use crate::{MessageType, ProtocolError};
+use std::collections::HashMap;
+
+const PADDING_MARKER: u8 = 0x80;
+pub const DEFAULT_PADDING_BUCKETS: [usize; 5] = [128, 512, 2048, 8192, 32768];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaddingPolicy {
+    // Records of these types are always padded to the same length, unless they don't fit in it
+    pub fixed: HashMap<MessageType, usize>,
+    // Anything else is padded up to the next of these lengths, or a multiple of the largest. With
+    // none, records are left as they are.
+    pub buckets: Vec<usize>,
+}
+
+impl Default for PaddingPolicy {
+    fn default() -> Self {
+        PaddingPolicy::buckets(DEFAULT_PADDING_BUCKETS.to_vec())
+    }
+}
+
+impl PaddingPolicy {
+    pub fn none() -> Self {
+        PaddingPolicy::buckets(Vec::new())
+    }
+
+    pub fn buckets(mut buckets: Vec<usize>) -> Self {
+        buckets.sort_unstable();
+        buckets.retain(|bucket| *bucket > 0);
+        PaddingPolicy {
+            fixed: HashMap::new(),
+            buckets,
+        }
+    }
+
+    // How long a record of len bytes, including the marker, should be made. Never more than max_len
+    // unless it is already longer.
+    pub fn padded_len(&self, message_type: MessageType, len: usize, max_len: usize) -> usize {
+        let target: usize = match self.fixed.get(&message_type) {
+            Some(fixed) if *fixed >= len => *fixed,
+            _ => match self.buckets.iter().find(|bucket| **bucket >= len) {
+                Some(bucket) => *bucket,
+                None => match self.buckets.last() {
+                    Some(largest) => len.div_ceil(*largest) * largest,
+                    None => len,
+                },
+            },
+        };
+        target.min(max_len).max(len)
+    }
+
+    // Adds the marker and padding to a record
+    pub fn pad(&self, record: &mut Vec<u8>, message_type: MessageType, max_len: usize) {
+        record.push(PADDING_MARKER);
+        let padded_len: usize = self.padded_len(message_type, record.len(), max_len);
+        record.resize(padded_len, 0);
+    }
+}
+
+// Strips the padding from a received record
+pub fn unpad(record: &mut Vec<u8>) -> Result<(), ProtocolError> {
+    let marker: usize = record
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .filter(|position| record[*position] == PADDING_MARKER)
+        .ok_or_else(|| ProtocolError::Malformed("record padding is missing".to_string()))?;
+    record.truncate(marker);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_padded_to_their_bucket_and_stripped() {
+        let policy: PaddingPolicy = PaddingPolicy::buckets(vec![512, 128]);
+        let mut lengths: Vec<usize> = Vec::new();
+        for len in [0, 5, 127, 128, 300, 1000] {
+            let original: Vec<u8> = vec![0; len];
+            let mut record: Vec<u8> = original.clone();
+            policy.pad(&mut record, MessageType::Secret, 4096);
+            lengths.push(record.len());
+            unpad(&mut record).unwrap();
+            assert_eq!(record, original);
+        }
+        // Beyond the largest bucket, a multiple of it
+        assert_eq!(lengths, [128, 128, 128, 512, 512, 1024]);
+        // Never past the limit, nor cut short by it
+        assert_eq!(policy.padded_len(MessageType::NORMAL, 600, 700), 700);
+        assert_eq!(policy.padded_len(MessageType::NORMAL, 800, 700), 800);
+        assert_eq!(
+            PaddingPolicy::none().padded_len(MessageType::NORMAL, 33, 700),
+            33
+        );
+    }
+
+    #[test]
+    fn fixed_sizes_take_precedence_for_their_types() {
+        let mut policy: PaddingPolicy = PaddingPolicy::default();
+        policy.fixed.insert(MessageType::Secret, 300);
+        assert_eq!(policy.padded_len(MessageType::Secret, 20, 4096), 300);
+        assert_eq!(policy.padded_len(MessageType::Secret, 290, 4096), 300);
+        assert_eq!(policy.padded_len(MessageType::Ping, 20, 4096), 128);
+        // Too big for its fixed size, so it falls back to the buckets
+        assert_eq!(policy.padded_len(MessageType::Secret, 400, 4096), 512);
+        // A record whose padding was removed on the way
+        let mut record: Vec<u8> = b"body".to_vec();
+        assert!(unpad(&mut record).is_err());
+        let mut record: Vec<u8> = vec![0; 16];
+        assert!(unpad(&mut record).is_err());
+    }
+}
@@ -0,0 +1,462 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Typed contents for the messages which carry structured data.
+//
+// Each payload is a sequence of fields with no padding in between:
+//   string / bytes  u32 big-endian length, then that many bytes (strings are UTF-8)
+//   public key      as bytes, holding the DER encoded SubjectPublicKeyInfo of an RSA key
+//   code            a single byte
+//   number          u64 big-endian
+// A payload must be consumed exactly, trailing bytes are an error.
+//
+//   AddPeer          address: string | public key
+//   RemovePeer       address: string | public key
+//   InformAddress    address: string
+//   InformPublicKey  public key
+//   Secret           secret: bytes
+//   Goodbye          reason: code
+//   Ack              message ID: number
+//   Request / Reply  call ID: number | message type: code | content: bytes (see rpc.rs)
+//   TransferStart    transfer ID: number | message ID: number | message type: code |
+//                    length: number | chunks: number | SHA-256: bytes (see transfer.rs)
+//   TransferChunk    transfer ID: number | index: number | data: bytes
+//   KeyUpdate        generation: number (see record.rs)
+
+use crate::{Message, MessageType, ProtocolError};
+use openssl::pkey::Public;
+use openssl::rsa::Rsa;
+use std::fmt;
+
+#[derive(Debug, Default)]
+pub struct PayloadWriter {
+    bytes: Vec<u8>,
+}
+
+impl PayloadWriter {
+    pub fn new() -> Self {
+        PayloadWriter { bytes: Vec::new() }
+    }
+
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        // Payloads are bounded by the frame size, far below u32::MAX
+        self.bytes
+            .extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn put_string(&mut self, string: &str) {
+        self.put_bytes(string.as_bytes());
+    }
+
+    pub fn put_public_key(&mut self, public_key: &Rsa<Public>) {
+        // Encoding a key we already hold in memory cannot fail
+        self.put_bytes(&public_key.public_key_to_der().unwrap());
+    }
+
+    pub fn put_code(&mut self, code: u8) {
+        self.bytes.push(code);
+    }
+
+    pub fn put_number(&mut self, number: u64) {
+        self.bytes.extend_from_slice(&number.to_be_bytes());
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+#[derive(Debug)]
+pub struct PayloadReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> PayloadReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        PayloadReader { bytes }
+    }
+
+    pub fn take_bytes(&mut self) -> Result<&'a [u8], ProtocolError> {
+        if self.bytes.len() < 4 {
+            return Err(ProtocolError::Malformed(
+                "payload ended before a field's length".to_string(),
+            ));
+        }
+        let (length_bytes, rest) = self.bytes.split_at(4);
+        let len: usize = u32::from_be_bytes([
+            length_bytes[0],
+            length_bytes[1],
+            length_bytes[2],
+            length_bytes[3],
+        ]) as usize;
+        if rest.len() < len {
+            return Err(ProtocolError::Malformed(
+                "payload ended part way through a field".to_string(),
+            ));
+        }
+        let (field, rest) = rest.split_at(len);
+        self.bytes = rest;
+        Ok(field)
+    }
+
+    pub fn take_string(&mut self) -> Result<String, ProtocolError> {
+        String::from_utf8(self.take_bytes()?.to_vec())
+            .map_err(|_| ProtocolError::Malformed("string field is not UTF-8".to_string()))
+    }
+
+    pub fn take_public_key(&mut self) -> Result<Rsa<Public>, ProtocolError> {
+        Rsa::public_key_from_der(self.take_bytes()?)
+            .map_err(|_| ProtocolError::Malformed("invalid RSA public key".to_string()))
+    }
+
+    pub fn take_code(&mut self) -> Result<u8, ProtocolError> {
+        let (code, rest) = self
+            .bytes
+            .split_first()
+            .ok_or_else(|| ProtocolError::Malformed("payload ended before a code".to_string()))?;
+        self.bytes = rest;
+        Ok(*code)
+    }
+
+    pub fn take_number(&mut self) -> Result<u64, ProtocolError> {
+        if self.bytes.len() < 8 {
+            return Err(ProtocolError::Malformed(
+                "payload ended part way through a number".to_string(),
+            ));
+        }
+        let (number_bytes, rest) = self.bytes.split_at(8);
+        let mut number: [u8; 8] = [0; 8];
+        number.copy_from_slice(number_bytes);
+        self.bytes = rest;
+        Ok(u64::from_be_bytes(number))
+    }
+
+    pub fn finish(self) -> Result<(), ProtocolError> {
+        if !self.bytes.is_empty() {
+            return Err(ProtocolError::Malformed(format!(
+                "{} unexpected bytes after the payload",
+                self.bytes.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+pub trait Payload: Sized {
+    const MESSAGE_TYPE: MessageType;
+
+    fn encode(&self, writer: &mut PayloadWriter);
+
+    fn decode(reader: &mut PayloadReader) -> Result<Self, ProtocolError>;
+
+    fn to_message(&self) -> Message {
+        let mut writer: PayloadWriter = PayloadWriter::new();
+        self.encode(&mut writer);
+        Message::new(writer.into_bytes(), Self::MESSAGE_TYPE)
+    }
+
+    fn from_message(message: &Message) -> Result<Self, ProtocolError> {
+        if message.message_type != Self::MESSAGE_TYPE {
+            return Err(ProtocolError::Malformed(format!(
+                "expected a {:?} message, got {:?}",
+                Self::MESSAGE_TYPE,
+                message.message_type
+            )));
+        }
+        let mut reader: PayloadReader = PayloadReader::new(&message.content);
+        let payload: Self = Self::decode(&mut reader)?;
+        reader.finish()?;
+        Ok(payload)
+    }
+}
+
+// Sent by the server to tell existing clients how to reach a new one
+#[derive(Debug, Clone)]
+pub struct AddPeer {
+    pub address: String,
+    pub public_key: Rsa<Public>,
+}
+
+impl Payload for AddPeer {
+    const MESSAGE_TYPE: MessageType = MessageType::AddPeer;
+
+    fn encode(&self, writer: &mut PayloadWriter) {
+        writer.put_string(&self.address);
+        writer.put_public_key(&self.public_key);
+    }
+
+    fn decode(reader: &mut PayloadReader) -> Result<Self, ProtocolError> {
+        Ok(AddPeer {
+            address: reader.take_string()?,
+            public_key: reader.take_public_key()?,
+        })
+    }
+}
+
+// Sent by the server when a client leaves. Peers are identified by their public key, as that is
+// known for both the peers we connected to and the peers which connected to us.
+#[derive(Debug, Clone)]
+pub struct RemovePeer {
+    pub address: String,
+    pub public_key: Rsa<Public>,
+}
+
+impl Payload for RemovePeer {
+    const MESSAGE_TYPE: MessageType = MessageType::RemovePeer;
+
+    fn encode(&self, writer: &mut PayloadWriter) {
+        writer.put_string(&self.address);
+        writer.put_public_key(&self.public_key);
+    }
+
+    fn decode(reader: &mut PayloadReader) -> Result<Self, ProtocolError> {
+        Ok(RemovePeer {
+            address: reader.take_string()?,
+            public_key: reader.take_public_key()?,
+        })
+    }
+}
+
+// Sent by a client to tell the server where its peer listener is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InformAddress {
+    pub address: String,
+}
+
+impl Payload for InformAddress {
+    const MESSAGE_TYPE: MessageType = MessageType::InformAddress;
+
+    fn encode(&self, writer: &mut PayloadWriter) {
+        writer.put_string(&self.address);
+    }
+
+    fn decode(reader: &mut PayloadReader) -> Result<Self, ProtocolError> {
+        Ok(InformAddress {
+            address: reader.take_string()?,
+        })
+    }
+}
+
+// Sent by a client to the server, or to a peer which asked with RequestPublicKey
+#[derive(Debug, Clone)]
+pub struct InformPublicKey {
+    pub public_key: Rsa<Public>,
+}
+
+impl Payload for InformPublicKey {
+    const MESSAGE_TYPE: MessageType = MessageType::InformPublicKey;
+
+    fn encode(&self, writer: &mut PayloadWriter) {
+        writer.put_public_key(&self.public_key);
+    }
+
+    fn decode(reader: &mut PayloadReader) -> Result<Self, ProtocolError> {
+        Ok(InformPublicKey {
+            public_key: reader.take_public_key()?,
+        })
+    }
+}
+
+// Sent by a client to the server, which reports a match when two clients send the same secret
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Secret {
+    pub secret: Vec<u8>,
+}
+
+impl Payload for Secret {
+    const MESSAGE_TYPE: MessageType = MessageType::Secret;
+
+    fn encode(&self, writer: &mut PayloadWriter) {
+        writer.put_bytes(&self.secret);
+    }
+
+    fn decode(reader: &mut PayloadReader) -> Result<Self, ProtocolError> {
+        Ok(Secret {
+            secret: reader.take_bytes()?.to_vec(),
+        })
+    }
+}
+
+// Why a connection is being closed. Codes we don't know yet are kept, so a newer reason still ends
+// the connection cleanly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GoodbyeReason {
+    // The client is leaving
+    Leaving,
+    // The server is shutting down
+    ShuttingDown,
+    // Nothing was heard from the other end for too long
+    TimedOut,
+    // The other end sent something which broke the protocol
+    ProtocolViolation,
+    Other(u8),
+}
+
+impl GoodbyeReason {
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Leaving => 0,
+            Self::ShuttingDown => 1,
+            Self::TimedOut => 2,
+            Self::ProtocolViolation => 3,
+            Self::Other(code) => *code,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => Self::Leaving,
+            1 => Self::ShuttingDown,
+            2 => Self::TimedOut,
+            3 => Self::ProtocolViolation,
+            _ => Self::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for GoodbyeReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Leaving => write!(f, "leaving"),
+            Self::ShuttingDown => write!(f, "shutting down"),
+            Self::TimedOut => write!(f, "timed out"),
+            Self::ProtocolViolation => write!(f, "protocol violation"),
+            Self::Other(code) => write!(f, "reason {}", code),
+        }
+    }
+}
+
+// Sent by either end just before it closes the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Goodbye {
+    pub reason: GoodbyeReason,
+}
+
+impl Payload for Goodbye {
+    const MESSAGE_TYPE: MessageType = MessageType::Goodbye;
+
+    fn encode(&self, writer: &mut PayloadWriter) {
+        writer.put_code(self.reason.code());
+    }
+
+    fn decode(reader: &mut PayloadReader) -> Result<Self, ProtocolError> {
+        Ok(Goodbye {
+            reason: GoodbyeReason::from_code(reader.take_code()?),
+        })
+    }
+}
+
+// Sent back for every message which arrives with an ID, including repeats of one already seen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ack {
+    pub message_id: u64,
+}
+
+impl Payload for Ack {
+    const MESSAGE_TYPE: MessageType = MessageType::Ack;
+
+    fn encode(&self, writer: &mut PayloadWriter) {
+        writer.put_number(self.message_id);
+    }
+
+    fn decode(reader: &mut PayloadReader) -> Result<Self, ProtocolError> {
+        Ok(Ack {
+            message_id: reader.take_number()?,
+        })
+    }
+}
+
+// Sent by either end just before it starts sealing with its next key, numbering that key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyUpdate {
+    pub generation: u64,
+}
+
+impl Payload for KeyUpdate {
+    const MESSAGE_TYPE: MessageType = MessageType::KeyUpdate;
+
+    fn encode(&self, writer: &mut PayloadWriter) {
+        writer.put_number(self.generation);
+    }
+
+    fn decode(reader: &mut PayloadReader) -> Result<Self, ProtocolError> {
+        Ok(KeyUpdate {
+            generation: reader.take_number()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::Private;
+
+    #[test]
+    fn payloads_round_trip() {
+        let private_key: Rsa<Private> = Rsa::generate(1024).unwrap();
+        let public_key: Rsa<Public> =
+            Rsa::public_key_from_der(&private_key.public_key_to_der().unwrap()).unwrap();
+        let add_peer: AddPeer = AddPeer {
+            address: "127.0.0.1:4000".to_string(),
+            public_key,
+        };
+        let decoded: AddPeer = AddPeer::from_message(&add_peer.to_message()).unwrap();
+        assert_eq!(decoded.address, add_peer.address);
+        assert_eq!(
+            decoded.public_key.public_key_to_der().unwrap(),
+            add_peer.public_key.public_key_to_der().unwrap()
+        );
+        let secret: Secret = Secret {
+            secret: b"alice,bob".to_vec(),
+        };
+        assert_eq!(Secret::from_message(&secret.to_message()).unwrap(), secret);
+        for reason in [GoodbyeReason::ShuttingDown, GoodbyeReason::Other(200)] {
+            let goodbye: Goodbye = Goodbye { reason };
+            assert_eq!(
+                Goodbye::from_message(&goodbye.to_message()).unwrap(),
+                goodbye
+            );
+        }
+        let ack: Ack = Ack {
+            message_id: 1 << 40,
+        };
+        assert_eq!(Ack::from_message(&ack.to_message()).unwrap(), ack);
+        let key_update: KeyUpdate = KeyUpdate { generation: 3 };
+        assert_eq!(
+            KeyUpdate::from_message(&key_update.to_message()).unwrap(),
+            key_update
+        );
+    }
+
+    #[test]
+    fn bad_payloads_are_rejected() {
+        let address: InformAddress = InformAddress {
+            address: "somewhere".to_string(),
+        };
+        let mut message: Message = address.to_message();
+        // The wrong message type
+        assert!(Secret::from_message(&message).is_err());
+        // Trailing bytes
+        message.content.push(0);
+        assert!(InformAddress::from_message(&message).is_err());
+        // A length running past the end
+        message.content = vec![0, 0, 1, 0, b'a'];
+        assert!(InformAddress::from_message(&message).is_err());
+        // Not a key
+        message = Message::new(vec![0, 0, 0, 1, 0], MessageType::InformPublicKey);
+        assert!(InformPublicKey::from_message(&message).is_err());
+    }
+}
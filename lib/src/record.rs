@@ -0,0 +1,421 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Turning messages into encrypted frames and back, without doing any I/O, so the blocking
+// SecureChannel and the AsyncSecureChannel share one implementation of the protocol.
+// Each message is sent as a single frame, with the header and body sealed together as one AEAD
+// record so a header can never be paired with the body of a different frame. Messages too large
+// for a frame are sent as a transfer of smaller ones (see transfer.rs), a few pieces at a time so
+// other messages can go out in between, and put back together before they are handed over. The
+// record is bound to its position on the connection by the sequence numbers. If both ends support it, bodies are
+// compressed before they are sealed (see compression.rs), and records are padded according to
+// the PaddingPolicy.
+// Messages which must not go missing are sent with an ID, and kept until the other end
+// acknowledges them. The Acks we owe are queued until they have actually been sent, so a message
+// is still handed over if its Ack can't go out straight away. The receiver remembers which IDs it
+// has handed over rather than just the highest, as a message sent again can arrive after later
+// ones.
+// Each direction has its own key. Once the sender has sent as much as its RekeyPolicy allows under
+// one key, it sends a KeyUpdate sealed with that key and seals everything after it with the next
+// key (see handshake::next_key). The receiver moves on when the KeyUpdate arrives, so the limits
+// are up to the sender alone.
+
+use crate::compression;
+use crate::handshake::{self, Session, FEATURE_COMPRESSION, FEATURE_PADDING};
+use crate::padding::{self, PaddingPolicy};
+use crate::payloads::{Ack, KeyUpdate, Payload};
+use crate::transfer::{Progress, Transfers};
+use crate::{
+    Message, MessageHeader, MessageType, ProtocolError, SequenceNumbers, AES_OVERHEAD,
+    MESSAGE_HEADER_LEN, SERVER_LINK_MAX_TRANSFER_LEN,
+};
+use std::collections::{BTreeSet, VecDeque};
+use std::env;
+use std::time::{Duration, Instant};
+
+// How long a message sent with an ID waits for its Ack before it is sent again
+pub const RETRANSMIT_AFTER: Duration = Duration::from_secs(2);
+// How far past the oldest ID still missing a message may be
+pub const RECEIVE_WINDOW: u64 = 1 << 16;
+// How many pieces of transfers go out with each send or receive
+pub const TRANSFER_PIECES_PER_TURN: usize = 16;
+pub const DEFAULT_REKEY_RECORDS: u64 = 1 << 16;
+pub const DEFAULT_REKEY_BYTES: u64 = 1 << 28;
+// Overriding the defaults above
+pub const REKEY_RECORDS_VARIABLE: &str = "CRUSH_REKEY_RECORDS";
+pub const REKEY_BYTES_VARIABLE: &str = "CRUSH_REKEY_BYTES";
+
+// How much is sent under one key before moving on to the next. Whichever limit is reached first
+// starts the rekey.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    pub max_records: u64,
+    pub max_bytes: u64,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        RekeyPolicy {
+            max_records: DEFAULT_REKEY_RECORDS,
+            max_bytes: DEFAULT_REKEY_BYTES,
+        }
+    }
+}
+
+impl RekeyPolicy {
+    // The defaults, with anything set in the environment taking their place
+    pub fn from_env() -> Self {
+        let limit = |variable: &str| -> Option<u64> {
+            env::var(variable)
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .filter(|limit| *limit > 0)
+        };
+        RekeyPolicy {
+            max_records: limit(REKEY_RECORDS_VARIABLE).unwrap_or(DEFAULT_REKEY_RECORDS),
+            max_bytes: limit(REKEY_BYTES_VARIABLE).unwrap_or(DEFAULT_REKEY_BYTES),
+        }
+    }
+
+    pub fn never() -> Self {
+        RekeyPolicy {
+            max_records: u64::MAX,
+            max_bytes: u64::MAX,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RecordLayer {
+    session: Session,
+    pub(crate) sequence: SequenceNumbers,
+    last_sent_id: u64,
+    // Every ID up to the floor has been handed over, along with those in the set above it
+    received_floor: u64,
+    received_above: BTreeSet<u64>,
+    pub(crate) unacknowledged: VecDeque<Unacknowledged>,
+    // IDs of messages received which still need an Ack sending back
+    owed_acks: VecDeque<u64>,
+    padding: PaddingPolicy,
+    rekey: RekeyPolicy,
+    // Sent under the current send key
+    sent_records: u64,
+    sent_bytes: u64,
+    // How many times each direction has been rekeyed
+    send_generation: u64,
+    receive_generation: u64,
+    transfers: Transfers,
+    // Taken from the transfers but not written yet
+    transfer_pieces: VecDeque<Message>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Unacknowledged {
+    id: u64,
+    message: Message,
+    pub(crate) sent_at: Instant,
+}
+
+impl RecordLayer {
+    pub fn new(session: Session) -> Self {
+        let sequence: SequenceNumbers = SequenceNumbers::new(session.role);
+        RecordLayer {
+            session,
+            sequence,
+            last_sent_id: 0,
+            received_floor: 0,
+            received_above: BTreeSet::new(),
+            unacknowledged: VecDeque::new(),
+            owed_acks: VecDeque::new(),
+            padding: PaddingPolicy::default(),
+            rekey: RekeyPolicy::default(),
+            sent_records: 0,
+            sent_bytes: 0,
+            send_generation: 0,
+            receive_generation: 0,
+            transfers: Transfers::new(SERVER_LINK_MAX_TRANSFER_LEN),
+            transfer_pieces: VecDeque::new(),
+        }
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn set_padding_policy(&mut self, padding: PaddingPolicy) {
+        self.padding = padding;
+    }
+
+    pub fn set_rekey_policy(&mut self, rekey: RekeyPolicy) {
+        self.rekey = rekey;
+    }
+
+    // Keeps a message to be sent with the returned ID until the other end acknowledges it
+    pub fn track(&mut self, message: &Message) -> u64 {
+        self.last_sent_id += 1;
+        self.unacknowledged.push_back(Unacknowledged {
+            id: self.last_sent_id,
+            message: message.clone(),
+            sent_at: Instant::now(),
+        });
+        self.last_sent_id
+    }
+
+    pub fn is_acknowledged(&self, id: u64) -> bool {
+        !self.unacknowledged.iter().any(|pending| pending.id == id)
+    }
+
+    // Anything which has waited too long for its Ack, counting it as sent again. The wait only
+    // starts once the last of a transfer has been queued.
+    pub fn overdue(&mut self) -> Vec<(u64, Message)> {
+        let now: Instant = Instant::now();
+        for pending in self.unacknowledged.iter_mut() {
+            if self.transfers.is_sending(pending.id) {
+                pending.sent_at = now;
+            }
+        }
+        self.unacknowledged
+            .iter_mut()
+            .filter(|pending| now.duration_since(pending.sent_at) >= RETRANSMIT_AFTER)
+            .map(|pending| {
+                pending.sent_at = now;
+                (pending.id, pending.message.clone())
+            })
+            .collect()
+    }
+
+    pub fn fits(&self, message: &Message, max_frame_len: usize) -> bool {
+        // Compression and padding each add at most a byte
+        let extra_len: usize = self.compressed() as usize + self.padded() as usize;
+        AES_OVERHEAD + MESSAGE_HEADER_LEN + extra_len + message.content.len() <= max_frame_len
+    }
+
+    // Queues a message which doesn't fit in a frame to be sent as a transfer
+    pub fn queue_transfer(&mut self, message: Message, message_id: u64) {
+        self.transfers.send(message, message_id);
+    }
+
+    // The next piece of a transfer to send, which stays next until transfer_piece_sent
+    pub fn transfer_piece(&mut self) -> Option<Message> {
+        if self.transfer_pieces.is_empty() {
+            self.transfer_pieces.extend(self.transfers.outgoing(1));
+        }
+        self.transfer_pieces.front().cloned()
+    }
+
+    pub fn transfer_piece_sent(&mut self) {
+        self.transfer_pieces.pop_front();
+    }
+
+    pub fn outgoing_transfers(&self) -> Vec<Progress> {
+        self.transfers.outgoing_progress()
+    }
+
+    pub fn incoming_transfers(&self) -> Vec<Progress> {
+        self.transfers.incoming_progress()
+    }
+
+    pub fn set_max_transfer_len(&mut self, max_transfer_len: usize) {
+        self.transfers.set_max_incoming_len(max_transfer_len);
+    }
+
+    // The frame for a message. The send sequence number is only moved on by sent, once the frame
+    // has actually gone out.
+    pub fn seal(
+        &self,
+        message: &Message,
+        message_id: u64,
+        max_frame_len: usize,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let message_header: MessageHeader =
+            MessageHeader::new(&message.content, message.message_type, message_id);
+        let mut record: Vec<u8> = Vec::with_capacity(MESSAGE_HEADER_LEN + message.content.len());
+        record.extend_from_slice(&message_header.as_bytes());
+        if self.compressed() {
+            // Compression must not let through anything the other end would refuse to expand
+            if message.content.len() > max_frame_len {
+                return Err(ProtocolError::Oversized {
+                    len: message.content.len(),
+                    max: max_frame_len,
+                });
+            }
+            record.extend_from_slice(&compression::encode(&message.content));
+        } else {
+            record.extend_from_slice(&message.content);
+        }
+        if self.padded() {
+            let max_len: usize = max_frame_len.saturating_sub(AES_OVERHEAD);
+            self.padding.pad(&mut record, message.message_type, max_len);
+        }
+        self.session
+            .suite
+            .aead
+            .seal(&record, &self.sequence.send_aad(), &self.session.send_key)
+    }
+
+    pub fn sent(&mut self, frame_len: usize) -> Result<(), ProtocolError> {
+        self.sent_records += 1;
+        self.sent_bytes += frame_len as u64;
+        self.sequence.advance_send()
+    }
+
+    pub fn rekey_due(&self) -> bool {
+        self.sent_records >= self.rekey.max_records || self.sent_bytes >= self.rekey.max_bytes
+    }
+
+    // The KeyUpdate frame to send before rekeying, which is still sealed with the current key
+    pub fn key_update(&self, max_frame_len: usize) -> Result<Vec<u8>, ProtocolError> {
+        let key_update: KeyUpdate = KeyUpdate {
+            generation: self.send_generation + 1,
+        };
+        self.seal(&key_update.to_message(), 0, max_frame_len)
+    }
+
+    // Once the KeyUpdate has gone out, everything after it is sealed with the next key
+    pub fn rekeyed(&mut self) -> Result<(), ProtocolError> {
+        self.sequence.advance_send()?;
+        self.session.send_key = handshake::next_key(&self.session.send_key)?;
+        self.send_generation += 1;
+        self.sent_records = 0;
+        self.sent_bytes = 0;
+        Ok(())
+    }
+
+    // The next Ack to send, which stays owed until ack_sent
+    pub fn owed_ack(&self) -> Option<Message> {
+        self.owed_acks.front().map(|message_id| {
+            Ack {
+                message_id: *message_id,
+            }
+            .to_message()
+        })
+    }
+
+    pub fn ack_sent(&mut self) {
+        self.owed_acks.pop_front();
+    }
+
+    // Opens a frame and deals with Acks, repeated messages and transfers, giving back the message if
+    // there is one to hand over
+    pub fn open(
+        &mut self,
+        frame: &[u8],
+        max_frame_len: usize,
+    ) -> Result<Option<Message>, ProtocolError> {
+        let (header, content) = self.open_record(frame, max_frame_len)?;
+        if let MessageType::Ack = header.message_type {
+            let ack: Ack = Ack::from_message(&Message::new(content, MessageType::Ack))?;
+            self.unacknowledged
+                .retain(|pending| pending.id != ack.message_id);
+            return Ok(None);
+        }
+        if let MessageType::KeyUpdate = header.message_type {
+            let key_update: KeyUpdate =
+                KeyUpdate::from_message(&Message::new(content, MessageType::KeyUpdate))?;
+            if key_update.generation != self.receive_generation + 1 {
+                return Err(ProtocolError::Malformed(format!(
+                    "key update to generation {} after generation {}",
+                    key_update.generation, self.receive_generation
+                )));
+            }
+            self.session.receive_key = handshake::next_key(&self.session.receive_key)?;
+            self.receive_generation = key_update.generation;
+            return Ok(None);
+        }
+        let message: Message = Message::new(content, header.message_type);
+        // A message sent as a transfer has its ID in the TransferStart rather than the header
+        let (message_id, message) = match header.message_type {
+            MessageType::TransferStart | MessageType::TransferChunk => {
+                match self.transfers.receive(message, Instant::now())? {
+                    Some(received) => received,
+                    None => return Ok(None),
+                }
+            }
+            _ => (header.message_id, message),
+        };
+        if message_id != 0 {
+            if message_id > self.received_floor + RECEIVE_WINDOW {
+                return Err(ProtocolError::Malformed(format!(
+                    "message {} is too far ahead of message {}",
+                    message_id,
+                    self.received_floor + 1
+                )));
+            }
+            // Acknowledged even if it is a repeat, as it may be our first Ack which was lost
+            self.owed_acks.push_back(message_id);
+            if !self.mark_received(message_id) {
+                return Ok(None);
+            }
+        }
+        Ok(Some(message))
+    }
+
+    // Whether the message with this ID is new, remembering it if so
+    fn mark_received(&mut self, message_id: u64) -> bool {
+        if message_id <= self.received_floor || !self.received_above.insert(message_id) {
+            return false;
+        }
+        while self.received_above.remove(&(self.received_floor + 1)) {
+            self.received_floor += 1;
+        }
+        true
+    }
+
+    fn open_record(
+        &mut self,
+        frame: &[u8],
+        max_frame_len: usize,
+    ) -> Result<(MessageHeader, Vec<u8>), ProtocolError> {
+        if frame.len() < AES_OVERHEAD + MESSAGE_HEADER_LEN {
+            return Err(ProtocolError::Malformed(format!(
+                "frame of {} bytes is too short",
+                frame.len()
+            )));
+        }
+        let mut record: Vec<u8> = self.session.suite.aead.open(
+            frame,
+            &self.sequence.receive_aad(),
+            &self.session.receive_key,
+        )?;
+        self.sequence.advance_receive()?;
+        if self.padded() {
+            padding::unpad(&mut record)?;
+            if record.len() < MESSAGE_HEADER_LEN {
+                return Err(ProtocolError::Malformed(
+                    "record is too short for its header".to_string(),
+                ));
+            }
+        }
+        let mut content: Vec<u8> = record.split_off(MESSAGE_HEADER_LEN);
+        if self.compressed() {
+            content = compression::decode(&content, max_frame_len)?;
+        }
+        let header: MessageHeader = MessageHeader::from_bytes(&record)?;
+        if header.message_len != content.len() {
+            return Err(ProtocolError::Malformed(
+                "message length does not match its header".to_string(),
+            ));
+        }
+        Ok((header, content))
+    }
+
+    fn compressed(&self) -> bool {
+        self.session.features & FEATURE_COMPRESSION != 0
+    }
+
+    fn padded(&self) -> bool {
+        self.session.features & FEATURE_PADDING != 0
+    }
+}
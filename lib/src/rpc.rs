@@ -0,0 +1,314 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Calls over a connection, where a request is matched to its reply by a call ID rather than by
+// being the next thing to arrive.
+// A request is wrapped in a Request message and answered with a Reply carrying the same call ID.
+// Whatever reads the connection passes everything it receives through Calls::dispatch, which hands
+// replies to the call waiting for them and gives back anything else to be handled as usual.
+
+use crate::payloads::{Payload, PayloadReader, PayloadWriter};
+use crate::{Message, MessageType, ProtocolError, SecureChannel};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn put_message(writer: &mut PayloadWriter, message: &Message) {
+    writer.put_code(message.message_type.as_bytes()[0]);
+    writer.put_bytes(&message.content);
+}
+
+fn take_message(reader: &mut PayloadReader) -> Result<Message, ProtocolError> {
+    let message_type: MessageType = MessageType::try_from(reader.take_code()?)?;
+    Ok(Message::new(reader.take_bytes()?.to_vec(), message_type))
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub call_id: u64,
+    pub message: Message,
+}
+
+impl Request {
+    // The Reply to send back with the answer to this request
+    pub fn reply(&self, message: Message) -> Message {
+        Reply {
+            call_id: self.call_id,
+            message,
+        }
+        .to_message()
+    }
+}
+
+impl Payload for Request {
+    const MESSAGE_TYPE: MessageType = MessageType::Request;
+
+    fn encode(&self, writer: &mut PayloadWriter) {
+        writer.put_number(self.call_id);
+        put_message(writer, &self.message);
+    }
+
+    fn decode(reader: &mut PayloadReader) -> Result<Self, ProtocolError> {
+        Ok(Request {
+            call_id: reader.take_number()?,
+            message: take_message(reader)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub call_id: u64,
+    pub message: Message,
+}
+
+impl Payload for Reply {
+    const MESSAGE_TYPE: MessageType = MessageType::Reply;
+
+    fn encode(&self, writer: &mut PayloadWriter) {
+        writer.put_number(self.call_id);
+        put_message(writer, &self.message);
+    }
+
+    fn decode(reader: &mut PayloadReader) -> Result<Self, ProtocolError> {
+        Ok(Reply {
+            call_id: reader.take_number()?,
+            message: take_message(reader)?,
+        })
+    }
+}
+
+// The calls made over one connection which are still waiting for their replies
+#[derive(Debug)]
+pub struct Calls {
+    timeout: Duration,
+    last_call_id: AtomicU64,
+    waiting: Mutex<HashMap<u64, Sender<Message>>>,
+}
+
+impl Default for Calls {
+    fn default() -> Self {
+        Calls::new(DEFAULT_CALL_TIMEOUT)
+    }
+}
+
+impl Calls {
+    pub fn new(timeout: Duration) -> Self {
+        Calls {
+            timeout,
+            last_call_id: AtomicU64::new(0),
+            waiting: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn call<S: Read + Write>(
+        self: &Arc<Self>,
+        channel: &mut SecureChannel<S>,
+        request: Message,
+    ) -> Result<PendingCall, ProtocolError> {
+        let call_id: u64 = self.last_call_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (sender, receiver) = mpsc::channel::<Message>();
+        self.waiting.lock().unwrap().insert(call_id, sender);
+        // Made before sending, so the call is forgotten again if the send fails
+        let pending: PendingCall = PendingCall {
+            call_id,
+            receiver,
+            deadline: Instant::now() + self.timeout,
+            calls: self.clone(),
+        };
+        channel.send(
+            Request {
+                call_id,
+                message: request,
+            }
+            .to_message(),
+        )?;
+        Ok(pending)
+    }
+
+    // Hands a reply to the call waiting for it and gives back anything else. Replies to calls which
+    // have already given up are dropped, and invalid ones are an error.
+    pub fn dispatch(&self, message: Message) -> Result<Option<Message>, ProtocolError> {
+        if message.message_type != MessageType::Reply {
+            return Ok(Some(message));
+        }
+        let reply: Reply = Reply::from_message(&message)?;
+        if let Some(sender) = self.waiting.lock().unwrap().remove(&reply.call_id) {
+            let _ = sender.send(reply.message);
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Debug)]
+pub struct PendingCall {
+    call_id: u64,
+    receiver: Receiver<Message>,
+    deadline: Instant,
+    calls: Arc<Calls>,
+}
+
+impl PendingCall {
+    // Waits for another thread reading the connection to dispatch the reply. That thread needs the
+    // connection, so it must not be locked while waiting.
+    pub fn await_reply(self) -> Result<Message, ProtocolError> {
+        let remaining: Duration = self.deadline.saturating_duration_since(Instant::now());
+        match self.receiver.recv_timeout(remaining) {
+            Ok(reply) => Ok(reply),
+            Err(RecvTimeoutError::Timeout) => Err(ProtocolError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Err(ProtocolError::Closed),
+        }
+    }
+
+    // Reads the connection while waiting, for when nothing else is reading it. Anything which isn't
+    // the reply is passed to unrelated, and an invalid reply ends the wait. The deadline is only
+    // checked between reads, so the connection should have a read timeout.
+    pub fn await_reply_on<S: Read + Write>(
+        self,
+        channel: &mut SecureChannel<S>,
+        mut unrelated: impl FnMut(Message),
+    ) -> Result<Message, ProtocolError> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(reply) => return Ok(reply),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Err(ProtocolError::Closed),
+            }
+            if Instant::now() >= self.deadline {
+                return Err(ProtocolError::Timeout);
+            }
+            match channel.recv() {
+                Ok(message) => {
+                    if let Some(other) = self.calls.dispatch(message)? {
+                        unrelated(other);
+                    }
+                }
+                Err(ProtocolError::Timeout) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        self.calls.waiting.lock().unwrap().remove(&self.call_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake;
+    use crate::FramedStream;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    fn channel_pair() -> (SecureChannel<UnixStream>, SecureChannel<UnixStream>) {
+        let (caller, answerer) = UnixStream::pair().unwrap();
+        caller
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let (initiator, responder) = handshake::test_sessions([4; 32], 0);
+        (
+            SecureChannel::new(FramedStream::new(caller), initiator),
+            SecureChannel::new(FramedStream::new(answerer), responder),
+        )
+    }
+
+    #[test]
+    fn replies_find_their_calls() {
+        let (mut caller, mut answerer) = channel_pair();
+        let calls: Arc<Calls> = Arc::new(Calls::default());
+        let first: PendingCall = calls
+            .call(
+                &mut caller,
+                Message::new(b"one".to_vec(), MessageType::NORMAL),
+            )
+            .unwrap();
+        let second: PendingCall = calls
+            .call(
+                &mut caller,
+                Message::new(b"two".to_vec(), MessageType::NORMAL),
+            )
+            .unwrap();
+        let first_request: Request = Request::from_message(&answerer.recv().unwrap()).unwrap();
+        let second_request: Request = Request::from_message(&answerer.recv().unwrap()).unwrap();
+        assert_eq!(second_request.message.content, b"two");
+        // Something unrelated, then the replies the other way round
+        answerer
+            .send(Message::new(b"news".to_vec(), MessageType::DEBUG))
+            .unwrap();
+        for (request, answer) in [(&second_request, b"2"), (&first_request, b"1")] {
+            answerer
+                .send(request.reply(Message::new(answer.to_vec(), MessageType::NORMAL)))
+                .unwrap();
+        }
+        let mut unrelated: Vec<Message> = Vec::new();
+        let reply: Message = second
+            .await_reply_on(&mut caller, |message| unrelated.push(message))
+            .unwrap();
+        assert_eq!(reply.content, b"2");
+        assert_eq!(unrelated.len(), 1);
+        assert_eq!(unrelated[0].content, b"news");
+        let reply: Message = first.await_reply_on(&mut caller, |_| panic!()).unwrap();
+        assert_eq!(reply.content, b"1");
+    }
+
+    #[test]
+    fn calls_time_out_and_late_replies_are_dropped() {
+        let (mut caller, _answerer) = channel_pair();
+        let calls: Arc<Calls> = Arc::new(Calls::new(Duration::from_millis(100)));
+        let request: Message = Message::new(Vec::new(), MessageType::RequestPublicKey);
+        // Answered by another thread dispatching what it reads
+        let pending: PendingCall = calls.call(&mut caller, request.clone()).unwrap();
+        let dispatcher: Arc<Calls> = calls.clone();
+        thread::spawn(move || {
+            let reply: Message = Request {
+                call_id: 1,
+                message: request,
+            }
+            .reply(Message::new(b"key".to_vec(), MessageType::InformPublicKey));
+            assert!(dispatcher.dispatch(reply).unwrap().is_none());
+        });
+        assert_eq!(pending.await_reply().unwrap().content, b"key");
+        // Never answered
+        let pending: PendingCall = calls
+            .call(&mut caller, Message::new(Vec::new(), MessageType::NORMAL))
+            .unwrap();
+        assert!(matches!(pending.await_reply(), Err(ProtocolError::Timeout)));
+        let late: Message = Request {
+            call_id: 2,
+            message: Message::new(Vec::new(), MessageType::NORMAL),
+        }
+        .reply(Message::new(Vec::new(), MessageType::NORMAL));
+        assert!(calls.dispatch(late).unwrap().is_none());
+        assert!(calls.waiting.lock().unwrap().is_empty());
+        // Other traffic is handed back
+        let other: Message = Message::new(b"hi".to_vec(), MessageType::NORMAL);
+        assert_eq!(calls.dispatch(other).unwrap().unwrap().content, b"hi");
+        // A reply which can't be decoded is left to the caller to report
+        let invalid: Message = Message::new(vec![1, 2], MessageType::Reply);
+        assert!(matches!(
+            calls.dispatch(invalid),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+}
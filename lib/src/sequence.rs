@@ -0,0 +1,103 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::ProtocolError;
+
+// Which end of the handshake we were, which tells apart the two directions of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+impl Role {
+    fn as_byte(self) -> u8 {
+        match self {
+            Self::Initiator => 0,
+            Self::Responder => 1,
+        }
+    }
+
+    pub fn other(self) -> Self {
+        match self {
+            Self::Initiator => Self::Responder,
+            Self::Responder => Self::Initiator,
+        }
+    }
+}
+
+pub const SEQUENCE_AAD_LEN: usize = 9;
+
+// Counts the messages sent and received on a connection.
+// The numbers are never sent, instead each frame is authenticated with the sender's role and its
+// sequence number as associated data. A receiver only accepts the exact next number from the other
+// end, so a replayed, reordered or dropped frame, or one of our own frames reflected back at us,
+// fails to decrypt.
+#[derive(Debug, Clone)]
+pub struct SequenceNumbers {
+    role: Role,
+    next_send: u64,
+    next_receive: u64,
+}
+
+impl SequenceNumbers {
+    pub fn new(role: Role) -> Self {
+        SequenceNumbers {
+            role,
+            next_send: 0,
+            next_receive: 0,
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    // The associated data for the next frame we send
+    pub fn send_aad(&self) -> [u8; SEQUENCE_AAD_LEN] {
+        aad(self.role, self.next_send)
+    }
+
+    // The associated data we expect on the next frame we receive
+    pub fn receive_aad(&self) -> [u8; SEQUENCE_AAD_LEN] {
+        aad(self.role.other(), self.next_receive)
+    }
+
+    // Only called once a frame has actually been sent, so a failed send can be retried
+    pub fn advance_send(&mut self) -> Result<(), ProtocolError> {
+        self.next_send = next(self.next_send)?;
+        Ok(())
+    }
+
+    // Only called once a frame has been authenticated, so junk injected into the stream does not
+    // throw the count out for the genuine frames around it
+    pub fn advance_receive(&mut self) -> Result<(), ProtocolError> {
+        self.next_receive = next(self.next_receive)?;
+        Ok(())
+    }
+}
+
+fn aad(role: Role, sequence_number: u64) -> [u8; SEQUENCE_AAD_LEN] {
+    let mut bytes: [u8; SEQUENCE_AAD_LEN] = [0; SEQUENCE_AAD_LEN];
+    bytes[0] = role.as_byte();
+    bytes[1..].copy_from_slice(&sequence_number.to_be_bytes());
+    bytes
+}
+
+fn next(sequence_number: u64) -> Result<u64, ProtocolError> {
+    sequence_number
+        .checked_add(1)
+        .ok_or_else(|| ProtocolError::Malformed("sequence numbers exhausted".to_string()))
+}
@@ -0,0 +1,164 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The cipher suites a connection can use, each pairing how the handshake agrees on a secret with
+// the AEAD which seals records. The initiator offers the suites it supports in order of
+// preference and the responder takes the first one it supports too (see handshake.rs).
+
+use crate::{open_with, seal_with, ProtocolError};
+use openssl::symm::Cipher;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyExchange {
+    // Ephemeral keys on both sides, signed by the responder's RSA key
+    X25519,
+    // A secret chosen by the initiator, encrypted to the responder's RSA key with OAEP. Not
+    // forward secret, so only offered after X25519.
+    RsaOaep,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aead {
+    Aes256Gcm,
+    // Fast in software, for hosts without AES instructions
+    ChaCha20Poly1305,
+}
+
+impl Aead {
+    pub fn cipher(self) -> Cipher {
+        match self {
+            Aead::Aes256Gcm => Cipher::aes_256_gcm(),
+            Aead::ChaCha20Poly1305 => Cipher::chacha20_poly1305(),
+        }
+    }
+
+    // Returns 12 bytes of IV, 16 bytes of tag and then the ciphertext, as encrypt_aes_with_aad does
+    pub fn seal(self, data: &[u8], aad: &[u8], key: &[u8; 32]) -> Result<Vec<u8>, ProtocolError> {
+        let mut tag: [u8; 16] = [0; 16];
+        seal_with(self.cipher(), data, aad, key, &mut tag)
+    }
+
+    pub fn open(self, data: &[u8], aad: &[u8], key: &[u8; 32]) -> Result<Vec<u8>, ProtocolError> {
+        open_with(self.cipher(), data, aad, key)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CipherSuite {
+    // How the suite is named on the wire
+    pub id: u16,
+    pub key_exchange: KeyExchange,
+    pub aead: Aead,
+}
+
+pub const X25519_AES_256_GCM: CipherSuite = CipherSuite {
+    id: 1,
+    key_exchange: KeyExchange::X25519,
+    aead: Aead::Aes256Gcm,
+};
+pub const X25519_CHACHA20_POLY1305: CipherSuite = CipherSuite {
+    id: 2,
+    key_exchange: KeyExchange::X25519,
+    aead: Aead::ChaCha20Poly1305,
+};
+pub const RSA_OAEP_AES_256_GCM: CipherSuite = CipherSuite {
+    id: 3,
+    key_exchange: KeyExchange::RsaOaep,
+    aead: Aead::Aes256Gcm,
+};
+pub const RSA_OAEP_CHACHA20_POLY1305: CipherSuite = CipherSuite {
+    id: 4,
+    key_exchange: KeyExchange::RsaOaep,
+    aead: Aead::ChaCha20Poly1305,
+};
+
+// Every suite this end supports
+pub const CIPHER_SUITES: [CipherSuite; 4] = [
+    X25519_AES_256_GCM,
+    X25519_CHACHA20_POLY1305,
+    RSA_OAEP_AES_256_GCM,
+    RSA_OAEP_CHACHA20_POLY1305,
+];
+
+impl Default for CipherSuite {
+    fn default() -> Self {
+        X25519_AES_256_GCM
+    }
+}
+
+impl CipherSuite {
+    pub fn from_id(id: u16) -> Option<Self> {
+        CIPHER_SUITES.iter().copied().find(|suite| suite.id == id)
+    }
+
+    // The suites to offer, best first: forward secret ones before the rest, and ChaCha20-Poly1305
+    // before AES-GCM where AES isn't done in hardware
+    pub fn preferred() -> Vec<CipherSuite> {
+        let mut suites: Vec<CipherSuite> = CIPHER_SUITES.to_vec();
+        let aes_first: bool = has_aes_instructions();
+        suites.sort_by_key(|suite| {
+            (
+                suite.key_exchange != KeyExchange::X25519,
+                (suite.aead == Aead::Aes256Gcm) != aes_first,
+            )
+        });
+        suites
+    }
+}
+
+fn has_aes_instructions() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        std::arch::is_x86_feature_detected!("aes")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_aead_round_trips_and_suites_are_ordered() {
+        let key: [u8; 32] = [7; 32];
+        for aead in [Aead::Aes256Gcm, Aead::ChaCha20Poly1305] {
+            let sealed: Vec<u8> = aead.seal(b"secret", b"aad", &key).unwrap();
+            assert_eq!(sealed.len(), crate::AES_OVERHEAD + 6);
+            assert_eq!(aead.open(&sealed, b"aad", &key).unwrap(), b"secret");
+            assert!(matches!(
+                aead.open(&sealed, b"other", &key),
+                Err(ProtocolError::AuthenticationFailed)
+            ));
+        }
+        // Sealed by one, it can't be opened by the other
+        let sealed: Vec<u8> = Aead::Aes256Gcm.seal(b"secret", &[], &key).unwrap();
+        assert!(Aead::ChaCha20Poly1305.open(&sealed, &[], &key).is_err());
+        let preferred: Vec<CipherSuite> = CipherSuite::preferred();
+        assert_eq!(preferred.len(), CIPHER_SUITES.len());
+        assert!(preferred[..2]
+            .iter()
+            .all(|suite| suite.key_exchange == KeyExchange::X25519));
+        assert_eq!(preferred[0].aead, preferred[2].aead);
+        assert_eq!(CipherSuite::from_id(4), Some(RSA_OAEP_CHACHA20_POLY1305));
+        assert_eq!(CipherSuite::from_id(0), None);
+    }
+}
@@ -0,0 +1,539 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// TLS 1.3 as a transport, selected by putting tls: in front of any other address
+// (e.g. tls:127.0.0.1:6666 or tls:unix:/run/crush.sock).
+// The usual handshake and message records still run inside the TLS stream, so a TLS link is
+// identical to any other once it is connected.
+//
+// The listening end presents a certificate for its RSA key, either one issued by a local CA or a
+// self-signed one made on the spot. The connecting end either checks the certificate against the
+// CA, or pins it to the RSA public key it already expects the other end to have (server.pub for
+// the server, the key from AddPeer for a peer), in which case who signed it does not matter.
+
+use crate::transport::{self, Listener, Transport, ACCEPT_HANDSHAKE_TIMEOUT};
+use crate::ProtocolError;
+use openssl::asn1::{Asn1Integer, Asn1Time};
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::rsa::Rsa;
+use openssl::ssl::{
+    HandshakeError, SslAcceptor, SslConnector, SslMethod, SslStream, SslVerifyMode, SslVersion,
+};
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{X509Builder, X509NameBuilder, X509StoreContextRef, X509};
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::Duration;
+
+pub const TLS_SCHEME: &str = "tls:";
+
+// How long a self-signed certificate lasts, it is remade every time the program starts
+const SELF_SIGNED_DAYS: u32 = 365;
+// The most OpenSSL seals into one record
+const MAX_RECORD_LEN: usize = 16 * 1024;
+
+// The certificate and key the listening end presents
+#[derive(Debug, Clone)]
+pub struct TlsIdentity {
+    certificate: X509,
+    key: PKey<Private>,
+}
+
+impl TlsIdentity {
+    // Makes a certificate for the key which is valid for localhost
+    pub fn self_signed(key: &Rsa<Private>) -> Result<Self, ProtocolError> {
+        let key: PKey<Private> = PKey::from_rsa(key.clone())?;
+        let mut name: X509NameBuilder = X509NameBuilder::new()?;
+        name.append_entry_by_text("CN", "localhost")?;
+        let name = name.build();
+        let mut serial: BigNum = BigNum::new()?;
+        serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+        let mut builder: X509Builder = X509Builder::new()?;
+        builder.set_version(2)?;
+        let serial: Asn1Integer = serial.to_asn1_integer()?;
+        let not_before: Asn1Time = Asn1Time::days_from_now(0)?;
+        let not_after: Asn1Time = Asn1Time::days_from_now(SELF_SIGNED_DAYS)?;
+        builder.set_serial_number(&serial)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        builder.set_pubkey(&key)?;
+        builder.set_not_before(&not_before)?;
+        builder.set_not_after(&not_after)?;
+        let alternative_names = SubjectAlternativeName::new()
+            .dns("localhost")
+            .ip("127.0.0.1")
+            .build(&builder.x509v3_context(None, None))?;
+        builder.append_extension(alternative_names)?;
+        builder.sign(&key, MessageDigest::sha256())?;
+        Ok(TlsIdentity {
+            certificate: builder.build(),
+            key,
+        })
+    }
+
+    // Uses a certificate issued for the key, e.g. by a local CA
+    pub fn from_pem_file(filepath: &str, key: &Rsa<Private>) -> Result<Self, ProtocolError> {
+        let certificate: X509 = load_certificates(filepath)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| ProtocolError::Malformed(format!("{} has no certificate", filepath)))?;
+        Ok(TlsIdentity {
+            certificate,
+            key: PKey::from_rsa(key.clone())?,
+        })
+    }
+
+    pub fn certificate(&self) -> &X509 {
+        &self.certificate
+    }
+}
+
+// What the connecting end accepts from the listening end
+#[derive(Debug, Clone)]
+pub enum TlsTrust {
+    // A certificate issued by one of these for the host being connected to
+    Authorities(Vec<X509>),
+    // A certificate for exactly this key, whoever issued it and whatever it is for
+    PinnedKey(Rsa<Public>),
+}
+
+pub fn load_certificates(filepath: &str) -> Result<Vec<X509>, ProtocolError> {
+    let mut file: File = File::open(filepath)?;
+    let mut file_contents: Vec<u8> = Vec::new();
+    file.read_to_end(&mut file_contents)?;
+    X509::stack_from_pem(&file_contents).map_err(|_| {
+        ProtocolError::Malformed(format!("{} is not a PEM-encoded certificate", filepath))
+    })
+}
+
+// The host name a certificate has to be issued for to be trusted at an address
+pub fn server_name(address: &str) -> &str {
+    let address: &str = address.strip_prefix(TLS_SCHEME).unwrap_or(address);
+    if address.starts_with("unix:") {
+        return "localhost";
+    }
+    let host: &str = match address.rsplit_once(':') {
+        Some((host, _)) => host,
+        None => address,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+// A TLS connection whose writes can time out without losing their place. OpenSSL seals the start
+// of a write into a record before sending it, and if sending times out the write has to be tried
+// again with the same bytes. So those bytes count as written, and are kept to finish before
+// anything else is taken.
+#[derive(Debug)]
+pub struct TlsStream {
+    stream: SslStream<Box<dyn Transport>>,
+    unfinished: Vec<u8>,
+}
+
+impl TlsStream {
+    fn new(stream: SslStream<Box<dyn Transport>>) -> Self {
+        TlsStream {
+            stream,
+            unfinished: Vec::new(),
+        }
+    }
+
+    fn finish_write(&mut self) -> io::Result<()> {
+        while !self.unfinished.is_empty() {
+            let n: usize = self.stream.write(&self.unfinished)?;
+            self.unfinished.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Nothing of buf is taken until the record OpenSSL is part way through has gone
+        self.finish_write()?;
+        match self.stream.write(buf) {
+            Err(err) if is_timeout(&err) && !buf.is_empty() => {
+                let taken: usize = buf.len().min(MAX_RECORD_LEN);
+                self.unfinished = buf[..taken].to_vec();
+                Ok(taken)
+            }
+            result => result,
+        }
+    }
+
+    // Bytes still unfinished after a timeout stay queued, and go out ahead of the next write
+    fn flush(&mut self) -> io::Result<()> {
+        match self.finish_write() {
+            Err(err) if !is_timeout(&err) => Err(err),
+            _ => self.stream.flush(),
+        }
+    }
+}
+
+impl Transport for TlsStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.get_ref().set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.get_ref().set_write_timeout(timeout)
+    }
+
+    fn peer_identity(&self) -> String {
+        format!("{}{}", TLS_SCHEME, self.stream.get_ref().peer_identity())
+    }
+
+    // Closes the underlying transport without a close_notify, which would need &mut self
+    fn shutdown(&self) -> io::Result<()> {
+        self.stream.get_ref().shutdown()
+    }
+}
+
+fn handshake_error<S>(err: HandshakeError<S>) -> ProtocolError {
+    match err {
+        HandshakeError::SetupFailure(err) => err.into(),
+        HandshakeError::Failure(stream) | HandshakeError::WouldBlock(stream) => {
+            if stream.ssl().verify_result().as_raw() != 0 {
+                return ProtocolError::AuthenticationFailed;
+            }
+            let err = stream.into_error();
+            match err.into_io_error() {
+                Ok(err) => err.into(),
+                Err(err) => ProtocolError::Io(io::Error::other(err)),
+            }
+        }
+    }
+}
+
+// Runs the TLS handshake over a transport which is already connected
+pub fn connect(
+    transport: Box<dyn Transport>,
+    server_name: &str,
+    trust: &TlsTrust,
+) -> Result<Box<dyn Transport>, ProtocolError> {
+    let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+    builder.set_min_proto_version(Some(SslVersion::TLS1_3))?;
+    let verify_hostname: bool = match trust {
+        TlsTrust::Authorities(certificates) => {
+            let mut store: X509StoreBuilder = X509StoreBuilder::new()?;
+            for certificate in certificates {
+                store.add_cert(certificate.clone())?;
+            }
+            builder.set_cert_store(store.build());
+            true
+        }
+        TlsTrust::PinnedKey(public_key) => {
+            let pinned: Vec<u8> = public_key.public_key_to_der()?;
+            builder.set_verify_callback(
+                SslVerifyMode::PEER,
+                move |_, context: &mut X509StoreContextRef| {
+                    // Only the certificate the other end proves it holds the key for matters
+                    if context.error_depth() != 0 {
+                        return true;
+                    }
+                    context
+                        .current_cert()
+                        .and_then(|certificate| certificate.public_key().ok())
+                        .and_then(|key| key.public_key_to_der().ok())
+                        .is_some_and(|der| der == pinned)
+                },
+            );
+            false
+        }
+    };
+    let stream: SslStream<Box<dyn Transport>> = builder
+        .build()
+        .configure()?
+        .verify_hostname(verify_hostname)
+        .connect(server_name, transport)
+        .map_err(handshake_error)?;
+    Ok(Box::new(TlsStream::new(stream)))
+}
+
+// Connects to an address as transport::connect does, running TLS over it if it starts with tls:
+pub fn connect_to(address: &str, trust: &TlsTrust) -> Result<Box<dyn Transport>, ProtocolError> {
+    match address.strip_prefix(TLS_SCHEME) {
+        Some(inner_address) => connect(
+            transport::connect(inner_address)?,
+            server_name(inner_address),
+            trust,
+        ),
+        None => Ok(transport::connect(address)?),
+    }
+}
+
+// Listens on an address as transport::bind does, running TLS over it if it starts with tls:.
+// The identity is only made if it is needed.
+pub fn bind_to<F>(address: &str, identity: F) -> Result<Box<dyn Listener>, ProtocolError>
+where
+    F: FnOnce() -> Result<TlsIdentity, ProtocolError>,
+{
+    match address.strip_prefix(TLS_SCHEME) {
+        Some(inner_address) => Ok(Box::new(TlsListener::new(
+            transport::bind(inner_address)?,
+            &identity()?,
+        )?)),
+        None => Ok(transport::bind(address)?),
+    }
+}
+
+// Hands out connections which have completed the TLS handshake
+pub struct TlsListener {
+    inner: Box<dyn Listener>,
+    acceptor: SslAcceptor,
+    handshake_timeout: Duration,
+}
+
+impl TlsListener {
+    pub fn new(inner: Box<dyn Listener>, identity: &TlsIdentity) -> Result<Self, ProtocolError> {
+        let mut builder = SslAcceptor::mozilla_modern_v5(SslMethod::tls_server())?;
+        builder.set_certificate(&identity.certificate)?;
+        builder.set_private_key(&identity.key)?;
+        builder.check_private_key()?;
+        Ok(TlsListener {
+            inner,
+            acceptor: builder.build(),
+            handshake_timeout: ACCEPT_HANDSHAKE_TIMEOUT,
+        })
+    }
+
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+}
+
+impl Listener for TlsListener {
+    // A connection which fails the TLS handshake, or doesn't finish it in time, is reported as an
+    // error and the listener carries on
+    fn accept(&self) -> io::Result<Box<dyn Transport>> {
+        let transport: Box<dyn Transport> = self.inner.accept()?;
+        transport.set_read_timeout(Some(self.handshake_timeout))?;
+        transport.set_write_timeout(Some(self.handshake_timeout))?;
+        match self.acceptor.accept(transport) {
+            Ok(stream) => {
+                // Whoever takes the connection sets its own timeouts
+                stream.get_ref().set_read_timeout(None)?;
+                stream.get_ref().set_write_timeout(None)?;
+                Ok(Box::new(TlsStream::new(stream)))
+            }
+            Err(err) => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                handshake_error(err).to_string(),
+            )),
+        }
+    }
+
+    fn local_address(&self) -> io::Result<String> {
+        Ok(format!("{}{}", TLS_SCHEME, self.inner.local_address()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FramedStream, MemoryListener, MemoryPipe};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn public_key(key: &Rsa<Private>) -> Rsa<Public> {
+        Rsa::public_key_from_der(&key.public_key_to_der().unwrap()).unwrap()
+    }
+
+    // Connects to a TLS listener over a memory pipe, and has a frame echoed back if that worked.
+    // The client waits for the echo before hanging up, as the server is still sending its session
+    // tickets after the handshake.
+    fn exchange(identity: TlsIdentity, trust: TlsTrust) -> Result<Vec<u8>, ProtocolError> {
+        let (listener, connector) = MemoryListener::new("memory:tls");
+        let listener: TlsListener = TlsListener::new(Box::new(listener), &identity).unwrap();
+        let server = thread::spawn(move || {
+            if let Ok(transport) = listener.accept() {
+                let mut accepted: FramedStream<Box<dyn Transport>> = FramedStream::new(transport);
+                let frame: Vec<u8> = accepted.read_frame().unwrap();
+                accepted.write_frame(&frame).unwrap();
+            }
+        });
+        let echoed: Result<Vec<u8>, ProtocolError> =
+            connect(Box::new(connector.connect()?), "localhost", &trust).and_then(|stream| {
+                let mut stream: FramedStream<Box<dyn Transport>> = FramedStream::new(stream);
+                stream.write_frame(b"over TLS")?;
+                stream.read_frame()
+            });
+        server.join().unwrap();
+        echoed
+    }
+
+    #[test]
+    fn pinned_keys_are_checked() {
+        let key: Rsa<Private> = Rsa::generate(2048).unwrap();
+        let identity: TlsIdentity = TlsIdentity::self_signed(&key).unwrap();
+        assert_eq!(
+            exchange(identity.clone(), TlsTrust::PinnedKey(public_key(&key))).unwrap(),
+            b"over TLS"
+        );
+        let other_key: Rsa<Private> = Rsa::generate(2048).unwrap();
+        assert!(matches!(
+            exchange(identity, TlsTrust::PinnedKey(public_key(&other_key))),
+            Err(ProtocolError::AuthenticationFailed)
+        ));
+    }
+
+    #[test]
+    fn certificates_are_checked_against_the_authorities() {
+        let key: Rsa<Private> = Rsa::generate(2048).unwrap();
+        let identity: TlsIdentity = TlsIdentity::self_signed(&key).unwrap();
+        let authority: X509 = identity.certificate().clone();
+        assert_eq!(
+            exchange(identity.clone(), TlsTrust::Authorities(vec![authority])).unwrap(),
+            b"over TLS"
+        );
+        assert!(matches!(
+            exchange(identity, TlsTrust::Authorities(Vec::new())),
+            Err(ProtocolError::AuthenticationFailed)
+        ));
+    }
+
+    #[test]
+    fn quiet_connections_do_not_hold_up_the_listener() {
+        let key: Rsa<Private> = Rsa::generate(2048).unwrap();
+        let identity: TlsIdentity = TlsIdentity::self_signed(&key).unwrap();
+        let (listener, connector) = MemoryListener::new("memory:tls");
+        let mut listener: TlsListener = TlsListener::new(Box::new(listener), &identity).unwrap();
+        listener.set_handshake_timeout(Duration::from_millis(50));
+        // Connects and never says anything
+        let _silent = connector.connect().unwrap();
+        assert!(listener.accept().is_err());
+        // The listener carries on with the next connection
+        listener.set_handshake_timeout(ACCEPT_HANDSHAKE_TIMEOUT);
+        let client = thread::spawn(move || {
+            let stream: Box<dyn Transport> = connect(
+                Box::new(connector.connect().unwrap()),
+                "localhost",
+                &TlsTrust::PinnedKey(public_key(&key)),
+            )
+            .unwrap();
+            let mut stream: FramedStream<Box<dyn Transport>> = FramedStream::new(stream);
+            stream.write_frame(b"late").unwrap();
+            stream.read_frame().unwrap()
+        });
+        let mut accepted: FramedStream<Box<dyn Transport>> =
+            FramedStream::new(listener.accept().unwrap());
+        assert_eq!(accepted.read_frame().unwrap(), b"late");
+        accepted.write_frame(b"ok").unwrap();
+        assert_eq!(client.join().unwrap(), b"ok");
+    }
+
+    // A pipe whose writes can be made to time out, as if the other end had stopped reading
+    #[derive(Debug)]
+    struct Stalling {
+        pipe: MemoryPipe,
+        stalled: Arc<AtomicBool>,
+    }
+
+    impl Read for Stalling {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.pipe.read(buf)
+        }
+    }
+
+    impl Write for Stalling {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.stalled.load(Ordering::Relaxed) {
+                return Err(io::Error::from(ErrorKind::WouldBlock));
+            }
+            self.pipe.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.pipe.flush()
+        }
+    }
+
+    impl Transport for Stalling {
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            self.pipe.set_read_timeout(timeout)
+        }
+
+        fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            self.pipe.set_write_timeout(timeout)
+        }
+
+        fn peer_identity(&self) -> String {
+            self.pipe.peer_identity()
+        }
+
+        fn shutdown(&self) -> io::Result<()> {
+            self.pipe.shutdown()
+        }
+    }
+
+    #[test]
+    fn frames_whose_writes_time_out_still_arrive_whole() {
+        let key: Rsa<Private> = Rsa::generate(2048).unwrap();
+        let identity: TlsIdentity = TlsIdentity::self_signed(&key).unwrap();
+        let (listener, connector) = MemoryListener::new("memory:tls");
+        let listener: TlsListener = TlsListener::new(Box::new(listener), &identity).unwrap();
+        let server = thread::spawn(move || {
+            let mut accepted: FramedStream<Box<dyn Transport>> =
+                FramedStream::new(listener.accept().unwrap());
+            [
+                accepted.read_frame().unwrap(),
+                accepted.read_frame().unwrap(),
+            ]
+        });
+        let stalled: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+        let stalling: Stalling = Stalling {
+            pipe: connector.connect().unwrap(),
+            stalled: stalled.clone(),
+        };
+        let stream: Box<dyn Transport> = connect(
+            Box::new(stalling),
+            "localhost",
+            &TlsTrust::PinnedKey(public_key(&key)),
+        )
+        .unwrap();
+        let mut stream: FramedStream<Box<dyn Transport>> = FramedStream::new(stream);
+        // OpenSSL has sealed the first frame by the time its write times out, so it is kept
+        // and finished before the second rather than being replaced by it
+        stalled.store(true, Ordering::Relaxed);
+        stream.write_frame(b"first").unwrap();
+        stalled.store(false, Ordering::Relaxed);
+        stream.write_frame(b"second").unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            [b"first".to_vec(), b"second".to_vec()]
+        );
+    }
+
+    #[test]
+    fn server_names_come_from_the_address() {
+        assert_eq!(server_name("tls:127.0.0.1:6666"), "127.0.0.1");
+        assert_eq!(server_name("tls:crush.example:6666"), "crush.example");
+        assert_eq!(server_name("tls:[::1]:6666"), "::1");
+        assert_eq!(server_name("tls:unix:/run/crush.sock"), "localhost");
+    }
+}
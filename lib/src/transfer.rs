@@ -0,0 +1,485 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Sending messages too large for a single frame.
+// A transfer starts with a TransferStart giving the wrapped message's ID, type, length, number of
+// chunks and SHA-256, followed by its chunks in order. The ID is that of a message sent reliably,
+// so it is acknowledged once it has all arrived rather than piece by piece. The receiver puts the message back together
+// and only hands it over once the length and hash match.
+// Chunks can be sent a few at a time, so other traffic can go out between them.
+// A transfer which stops arriving part way through is given up on, so it doesn't hold on to its
+// memory or its place among the transfers allowed at once. How much may be arriving at once is
+// set by whoever owns the Transfers, as it depends on the link.
+//
+// Transfers does no I/O itself. Messages received are passed through receive and what outgoing
+// gives is sent. The record layer does this for every message too large for one frame (see
+// record.rs), so the channels send and receive them like any other.
+
+use crate::payloads::{Payload, PayloadReader, PayloadWriter};
+use crate::{split_message, Message, MessageType, ProtocolError};
+use openssl::sha::sha256;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// Small enough to fit in a frame on either kind of link
+pub const CHUNK_LEN: usize = 16 * 1024;
+// The largest message which will be put back together
+pub const MAX_TRANSFER_LEN: usize = 64 * 1024 * 1024;
+// How many transfers may be arriving at once
+pub const MAX_INCOMING_TRANSFERS: usize = 8;
+// How long a transfer may go without a chunk arriving before it is dropped
+pub const TRANSFER_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferStart {
+    pub transfer_id: u64,
+    pub message_id: u64,
+    pub message_type: MessageType,
+    pub len: u64,
+    pub chunk_count: u64,
+    pub digest: [u8; 32],
+}
+
+impl Payload for TransferStart {
+    const MESSAGE_TYPE: MessageType = MessageType::TransferStart;
+
+    fn encode(&self, writer: &mut PayloadWriter) {
+        writer.put_number(self.transfer_id);
+        writer.put_number(self.message_id);
+        writer.put_code(self.message_type.as_bytes()[0]);
+        writer.put_number(self.len);
+        writer.put_number(self.chunk_count);
+        writer.put_bytes(&self.digest);
+    }
+
+    fn decode(reader: &mut PayloadReader) -> Result<Self, ProtocolError> {
+        Ok(TransferStart {
+            transfer_id: reader.take_number()?,
+            message_id: reader.take_number()?,
+            message_type: MessageType::try_from(reader.take_code()?)?,
+            len: reader.take_number()?,
+            chunk_count: reader.take_number()?,
+            digest: reader
+                .take_bytes()?
+                .try_into()
+                .map_err(|_| ProtocolError::Malformed("digest is not 32 bytes".to_string()))?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferChunk {
+    pub transfer_id: u64,
+    pub index: u64,
+    pub data: Vec<u8>,
+}
+
+impl Payload for TransferChunk {
+    const MESSAGE_TYPE: MessageType = MessageType::TransferChunk;
+
+    fn encode(&self, writer: &mut PayloadWriter) {
+        writer.put_number(self.transfer_id);
+        writer.put_number(self.index);
+        writer.put_bytes(&self.data);
+    }
+
+    fn decode(reader: &mut PayloadReader) -> Result<Self, ProtocolError> {
+        Ok(TransferChunk {
+            transfer_id: reader.take_number()?,
+            index: reader.take_number()?,
+            data: reader.take_bytes()?.to_vec(),
+        })
+    }
+}
+
+// How far through a transfer is, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub transfer_id: u64,
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Debug)]
+struct OutgoingTransfer {
+    transfer_id: u64,
+    message_id: u64,
+    // Taken once it has been sent
+    start: Option<Message>,
+    next_index: u64,
+    chunks: VecDeque<Vec<u8>>,
+    sent: usize,
+    total: usize,
+}
+
+#[derive(Debug)]
+struct IncomingTransfer {
+    start: TransferStart,
+    next_index: u64,
+    data: Vec<u8>,
+    last_received: Instant,
+}
+
+#[derive(Debug)]
+pub struct Transfers {
+    last_transfer_id: u64,
+    outgoing: VecDeque<OutgoingTransfer>,
+    // Keyed by the other end's transfer IDs
+    incoming: HashMap<u64, IncomingTransfer>,
+    // How large the transfers arriving at once may be between them
+    max_incoming_len: usize,
+}
+
+impl Transfers {
+    pub fn new(max_incoming_len: usize) -> Self {
+        Transfers {
+            last_transfer_id: 0,
+            outgoing: VecDeque::new(),
+            incoming: HashMap::new(),
+            max_incoming_len,
+        }
+    }
+
+    pub fn set_max_incoming_len(&mut self, max_incoming_len: usize) {
+        self.max_incoming_len = max_incoming_len;
+    }
+
+    // Queues a message to be sent in chunks, returning the ID to follow its progress by. The
+    // message ID is 0 unless the message was sent reliably.
+    pub fn send(&mut self, message: Message, message_id: u64) -> u64 {
+        self.last_transfer_id += 1;
+        let transfer_id: u64 = self.last_transfer_id;
+        let chunks: Vec<Vec<u8>> = split_message(&message.content, CHUNK_LEN);
+        let start: TransferStart = TransferStart {
+            transfer_id,
+            message_id,
+            message_type: message.message_type,
+            len: message.content.len() as u64,
+            chunk_count: chunks.len() as u64,
+            digest: sha256(&message.content),
+        };
+        self.outgoing.push_back(OutgoingTransfer {
+            transfer_id,
+            message_id,
+            start: Some(start.to_message()),
+            next_index: 0,
+            chunks: chunks.into(),
+            sent: 0,
+            total: message.content.len(),
+        });
+        transfer_id
+    }
+
+    // Up to max_chunks chunks, taking turns between transfers, after the start of any new ones
+    pub fn outgoing(&mut self, max_chunks: usize) -> Vec<Message> {
+        let mut messages: Vec<Message> = self
+            .outgoing
+            .iter_mut()
+            .filter_map(|transfer| transfer.start.take())
+            .collect();
+        let mut chunks_left: usize = max_chunks;
+        while chunks_left > 0 {
+            let Some(mut transfer) = self.outgoing.pop_front() else {
+                break;
+            };
+            if let Some(data) = transfer.chunks.pop_front() {
+                transfer.sent += data.len();
+                messages.push(
+                    TransferChunk {
+                        transfer_id: transfer.transfer_id,
+                        index: transfer.next_index,
+                        data,
+                    }
+                    .to_message(),
+                );
+                transfer.next_index += 1;
+                chunks_left -= 1;
+            }
+            if !transfer.chunks.is_empty() {
+                self.outgoing.push_back(transfer);
+            }
+        }
+        messages
+    }
+
+    // None once everything has been sent
+    pub fn progress(&self, transfer_id: u64) -> Option<Progress> {
+        self.outgoing
+            .iter()
+            .find(|transfer| transfer.transfer_id == transfer_id)
+            .map(|transfer| Progress {
+                transfer_id,
+                done: transfer.sent,
+                total: transfer.total,
+            })
+    }
+
+    pub fn outgoing_progress(&self) -> Vec<Progress> {
+        self.outgoing
+            .iter()
+            .map(|transfer| Progress {
+                transfer_id: transfer.transfer_id,
+                done: transfer.sent,
+                total: transfer.total,
+            })
+            .collect()
+    }
+
+    // Whether the reliable message with this ID still has chunks to send
+    pub fn is_sending(&self, message_id: u64) -> bool {
+        self.outgoing
+            .iter()
+            .any(|transfer| transfer.message_id == message_id)
+    }
+
+    // The transfers still arriving from the other end
+    pub fn incoming_progress(&self) -> Vec<Progress> {
+        self.incoming
+            .iter()
+            .map(|(transfer_id, transfer)| Progress {
+                transfer_id: *transfer_id,
+                done: transfer.data.len(),
+                total: transfer.start.len as usize,
+            })
+            .collect()
+    }
+
+    // Takes in transfer traffic, handing back each message with its message ID once all of it has
+    // arrived. Transfers which have gone quiet are dropped first.
+    pub fn receive(
+        &mut self,
+        message: Message,
+        now: Instant,
+    ) -> Result<Option<(u64, Message)>, ProtocolError> {
+        self.incoming.retain(|_, transfer| {
+            now.duration_since(transfer.last_received) < TRANSFER_IDLE_TIMEOUT
+        });
+        match message.message_type {
+            MessageType::TransferStart => {
+                let start: TransferStart = TransferStart::from_message(&message)?;
+                let len: usize = usize::try_from(start.len).unwrap_or(usize::MAX);
+                if len > MAX_TRANSFER_LEN {
+                    return Err(ProtocolError::Oversized {
+                        len,
+                        max: MAX_TRANSFER_LEN,
+                    });
+                }
+                // split_message always gives at least one chunk
+                if start.chunk_count != len.div_ceil(CHUNK_LEN).max(1) as u64 {
+                    return Err(ProtocolError::Malformed(format!(
+                        "transfer of {} bytes can't have {} chunks",
+                        len, start.chunk_count
+                    )));
+                }
+                let incoming_bytes: usize = self
+                    .incoming
+                    .values()
+                    .map(|transfer| transfer.start.len as usize)
+                    .sum();
+                if self.incoming.len() >= MAX_INCOMING_TRANSFERS
+                    || incoming_bytes + len > self.max_incoming_len
+                    || self.incoming.contains_key(&start.transfer_id)
+                {
+                    return Err(ProtocolError::Malformed(format!(
+                        "transfer {} can't be started",
+                        start.transfer_id
+                    )));
+                }
+                self.incoming.insert(
+                    start.transfer_id,
+                    IncomingTransfer {
+                        start,
+                        next_index: 0,
+                        data: Vec::new(),
+                        last_received: now,
+                    },
+                );
+                Ok(None)
+            }
+            MessageType::TransferChunk => {
+                let chunk: TransferChunk = TransferChunk::from_message(&message)?;
+                let transfer: &mut IncomingTransfer =
+                    self.incoming.get_mut(&chunk.transfer_id).ok_or_else(|| {
+                        ProtocolError::Malformed(format!(
+                            "chunk for unknown transfer {}",
+                            chunk.transfer_id
+                        ))
+                    })?;
+                if chunk.index != transfer.next_index
+                    || transfer.data.len() + chunk.data.len() > transfer.start.len as usize
+                {
+                    return Err(ProtocolError::Malformed(format!(
+                        "chunk {} of transfer {} doesn't fit",
+                        chunk.index, chunk.transfer_id
+                    )));
+                }
+                transfer.data.extend_from_slice(&chunk.data);
+                transfer.next_index += 1;
+                transfer.last_received = now;
+                if transfer.next_index < transfer.start.chunk_count {
+                    return Ok(None);
+                }
+                let transfer: IncomingTransfer = self.incoming.remove(&chunk.transfer_id).unwrap();
+                if transfer.data.len() as u64 != transfer.start.len
+                    || sha256(&transfer.data) != transfer.start.digest
+                {
+                    return Err(ProtocolError::Malformed(format!(
+                        "transfer {} failed its integrity check",
+                        chunk.transfer_id
+                    )));
+                }
+                Ok(Some((
+                    transfer.start.message_id,
+                    Message::new(transfer.data, transfer.start.message_type),
+                )))
+            }
+            message_type => Err(ProtocolError::Malformed(format!(
+                "{:?} is not part of a transfer",
+                message_type
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn large_messages_are_put_back_together() {
+        let mut sender: Transfers = Transfers::new(MAX_TRANSFER_LEN);
+        let mut receiver: Transfers = Transfers::new(MAX_TRANSFER_LEN);
+        let big: Vec<u8> = (0..5 * CHUNK_LEN + 100).map(|i| i as u8).collect();
+        let exact: Vec<u8> = vec![1; 2 * CHUNK_LEN];
+        let first: u64 = sender.send(Message::new(big.clone(), MessageType::NORMAL), 0);
+        let second: u64 = sender.send(Message::new(exact.clone(), MessageType::DEBUG), 7);
+        let mut arrived: Vec<(u64, Message)> = Vec::new();
+        let mut turns: usize = 0;
+        while sender.progress(first).is_some() || sender.progress(second).is_some() {
+            for message in sender.outgoing(2) {
+                // Every frame stays small
+                assert!(message.content.len() <= CHUNK_LEN + 64);
+                arrived.extend(receiver.receive(message, Instant::now()).unwrap());
+            }
+            turns += 1;
+        }
+        // Two chunks a turn, shared between the transfers
+        assert_eq!(turns, 4);
+        assert!(receiver.incoming_progress().is_empty());
+        // The shorter one finished first, as they took turns
+        assert_eq!(arrived.len(), 2);
+        assert_eq!(arrived[0].0, 7);
+        assert_eq!(arrived[0].1.message_type, MessageType::DEBUG);
+        assert_eq!(arrived[0].1.content, exact);
+        assert_eq!(arrived[1].0, 0);
+        assert_eq!(arrived[1].1.content, big);
+    }
+
+    #[test]
+    fn progress_is_reported_and_damage_is_caught() {
+        let mut sender: Transfers = Transfers::new(MAX_TRANSFER_LEN);
+        let mut receiver: Transfers = Transfers::new(MAX_TRANSFER_LEN);
+        let transfer_id: u64 =
+            sender.send(Message::new(vec![9; 3 * CHUNK_LEN], MessageType::NORMAL), 0);
+        let mut messages: Vec<Message> = sender.outgoing(1);
+        let progress: Progress = Progress {
+            transfer_id,
+            done: CHUNK_LEN,
+            total: 3 * CHUNK_LEN,
+        };
+        assert_eq!(sender.progress(transfer_id), Some(progress));
+        assert_eq!(sender.outgoing_progress(), [progress]);
+        for message in messages.drain(..) {
+            assert!(receiver.receive(message, Instant::now()).unwrap().is_none());
+        }
+        assert_eq!(receiver.incoming_progress()[0].done, CHUNK_LEN);
+        // A chunk whose contents changed on the way fails the hash once the rest arrives
+        messages = sender.outgoing(3);
+        let mut chunk: TransferChunk = TransferChunk::from_message(&messages[0]).unwrap();
+        chunk.data[0] ^= 1;
+        messages[0] = chunk.to_message();
+        let results: Vec<Result<Option<(u64, Message)>, ProtocolError>> = messages
+            .into_iter()
+            .map(|message| receiver.receive(message, Instant::now()))
+            .collect();
+        assert!(matches!(
+            results.last(),
+            Some(Err(ProtocolError::Malformed(_)))
+        ));
+        // Chunks out of order are refused straight away
+        sender.send(Message::new(vec![0; 2 * CHUNK_LEN], MessageType::NORMAL), 0);
+        let mut messages: Vec<Message> = sender.outgoing(2);
+        assert!(receiver
+            .receive(messages.remove(0), Instant::now())
+            .unwrap()
+            .is_none());
+        assert!(receiver
+            .receive(messages.remove(1), Instant::now())
+            .is_err());
+        // So are transfers larger than the limit
+        let huge: TransferStart = TransferStart {
+            transfer_id: 99,
+            message_id: 0,
+            message_type: MessageType::NORMAL,
+            len: MAX_TRANSFER_LEN as u64 + 1,
+            chunk_count: (MAX_TRANSFER_LEN / CHUNK_LEN) as u64 + 1,
+            digest: [0; 32],
+        };
+        assert!(matches!(
+            receiver.receive(huge.to_message(), Instant::now()),
+            Err(ProtocolError::Oversized { .. })
+        ));
+    }
+
+    #[test]
+    fn quiet_and_excessive_transfers_are_dropped() {
+        let mut sender: Transfers = Transfers::new(MAX_TRANSFER_LEN);
+        let mut receiver: Transfers = Transfers::new(MAX_TRANSFER_LEN);
+        let start: Instant = Instant::now();
+        sender.send(Message::new(vec![3; 2 * CHUNK_LEN], MessageType::NORMAL), 0);
+        let mut messages: Vec<Message> = sender.outgoing(1);
+        for message in messages.drain(..2) {
+            assert!(receiver.receive(message, start).unwrap().is_none());
+        }
+        assert_eq!(receiver.incoming_progress().len(), 1);
+        // Nothing more arrives for too long, so the rest of it is unknown by the time it does
+        let later: Instant = start + TRANSFER_IDLE_TIMEOUT;
+        assert!(matches!(
+            receiver.receive(sender.outgoing(1).remove(0), later),
+            Err(ProtocolError::Malformed(_))
+        ));
+        assert!(receiver.incoming_progress().is_empty());
+        // Transfers which would take more than the limit between them are refused
+        let large = |transfer_id: u64| TransferStart {
+            transfer_id,
+            message_id: 0,
+            message_type: MessageType::NORMAL,
+            len: MAX_TRANSFER_LEN as u64,
+            chunk_count: (MAX_TRANSFER_LEN / CHUNK_LEN) as u64,
+            digest: [0; 32],
+        };
+        receiver.set_max_incoming_len(2 * MAX_TRANSFER_LEN);
+        for transfer_id in 0..2 {
+            assert!(receiver
+                .receive(large(transfer_id).to_message(), later)
+                .unwrap()
+                .is_none());
+        }
+        assert!(matches!(
+            receiver.receive(large(99).to_message(), later),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+}
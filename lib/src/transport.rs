@@ -0,0 +1,463 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The byte streams the protocol can run over, and the listeners which hand them out.
+// Server and client code deals in Box<dyn Transport>, so TCP, Unix domain sockets and the
+// in-memory pipe used by tests can be mixed freely.
+//
+// Addresses are written as a scheme and a location, with no scheme meaning TCP:
+//   127.0.0.1:6666         a TCP socket
+//   unix:/run/crush.sock   a Unix domain socket at that path
+//   ws:127.0.0.1:6680      WebSockets over any of the above, see websocket.rs
+
+use crate::websocket::{self, WebSocketListener, WEBSOCKET_SCHEME};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// How long a listener waits for a new connection to finish a TLS or WebSocket handshake, so one
+// connection which goes quiet can't hold up everyone connecting after it
+pub const ACCEPT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub trait Transport: Read + Write + Send + Debug {
+    // A read which waits longer than this fails with a timeout, None waits forever
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    // Who is on the other end, for logging
+    fn peer_identity(&self) -> String;
+    // Closes both directions, which wakes anything blocked reading from either end
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_write_timeout(timeout)
+    }
+
+    fn peer_identity(&self) -> String {
+        (**self).peer_identity()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        (**self).shutdown()
+    }
+}
+
+impl Transport for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn peer_identity(&self) -> String {
+        match self.peer_addr() {
+            Ok(address) => address.to_string(),
+            Err(_) => "unknown TCP peer".to_string(),
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+impl Transport for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn peer_identity(&self) -> String {
+        // The connecting end of a Unix socket is usually unnamed
+        match self
+            .peer_addr()
+            .ok()
+            .and_then(|address| address.as_pathname().map(|path| path.display().to_string()))
+        {
+            Some(path) => format!("unix:{}", path),
+            None => "unnamed Unix socket".to_string(),
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+pub trait Listener: Send {
+    // Blocks until the next connection arrives
+    fn accept(&self) -> io::Result<Box<dyn Transport>>;
+    // The address other clients should be told to connect to
+    fn local_address(&self) -> io::Result<String>;
+}
+
+impl<L: Listener + ?Sized> Listener for Box<L> {
+    fn accept(&self) -> io::Result<Box<dyn Transport>> {
+        (**self).accept()
+    }
+
+    fn local_address(&self) -> io::Result<String> {
+        (**self).local_address()
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> io::Result<Box<dyn Transport>> {
+        let (stream, _) = TcpListener::accept(self)?;
+        Ok(Box::new(stream))
+    }
+
+    fn local_address(&self) -> io::Result<String> {
+        Ok(self.local_addr()?.to_string())
+    }
+}
+
+impl Listener for UnixListener {
+    fn accept(&self) -> io::Result<Box<dyn Transport>> {
+        let (stream, _) = UnixListener::accept(self)?;
+        Ok(Box::new(stream))
+    }
+
+    fn local_address(&self) -> io::Result<String> {
+        match self.local_addr()?.as_pathname() {
+            Some(path) => Ok(format!("unix:{}", path.display())),
+            None => Err(io::Error::new(
+                ErrorKind::AddrNotAvailable,
+                "Unix listener has no path",
+            )),
+        }
+    }
+}
+
+const UNIX_SCHEME: &str = "unix:";
+
+// Connects to an address in any of the forms described at the top of this file
+pub fn connect(address: &str) -> io::Result<Box<dyn Transport>> {
+    if let Some(inner_address) = address.strip_prefix(WEBSOCKET_SCHEME) {
+        let host: &str = match inner_address.starts_with(UNIX_SCHEME) {
+            true => "localhost",
+            false => inner_address,
+        };
+        return Ok(Box::new(websocket::connect(connect(inner_address)?, host)?));
+    }
+    match address.strip_prefix(UNIX_SCHEME) {
+        Some(path) => Ok(Box::new(UnixStream::connect(path)?)),
+        None => Ok(Box::new(TcpStream::connect(address)?)),
+    }
+}
+
+// Listens on an address in any of the forms described at the top of this file
+pub fn bind(address: &str) -> io::Result<Box<dyn Listener>> {
+    if let Some(inner_address) = address.strip_prefix(WEBSOCKET_SCHEME) {
+        return Ok(Box::new(WebSocketListener::new(bind(inner_address)?)));
+    }
+    match address.strip_prefix(UNIX_SCHEME) {
+        Some(path) => {
+            // A socket left behind by an earlier run would stop us binding, so it is removed once
+            // nothing answers on it. A live socket, or anything else at the path, is left alone.
+            if let Ok(metadata) = fs::symlink_metadata(path) {
+                if metadata.file_type().is_socket() {
+                    match UnixStream::connect(path) {
+                        Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                            fs::remove_file(path)?
+                        }
+                        _ => {
+                            return Err(io::Error::new(
+                                ErrorKind::AddrInUse,
+                                format!("{} is already being listened on", path),
+                            ))
+                        }
+                    }
+                }
+            }
+            Ok(Box::new(UnixListener::bind(path)?))
+        }
+        None => Ok(Box::new(TcpListener::bind(address)?)),
+    }
+}
+
+// One direction of a MemoryPipe
+#[derive(Debug, Default)]
+struct PipeBuffer {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+#[derive(Debug, Default)]
+struct Pipe {
+    buffer: Mutex<PipeBuffer>,
+    readable: Condvar,
+}
+
+impl Pipe {
+    fn close(&self) {
+        self.buffer.lock().unwrap().closed = true;
+        self.readable.notify_all();
+    }
+}
+
+static NEXT_PIPE_ID: AtomicUsize = AtomicUsize::new(0);
+
+// An in-memory duplex byte stream, for running both ends of a connection in one process.
+// Writes never block. Reads honour the read timeout, and see the end of the stream once the
+// other end is shut down or dropped and everything it wrote has been read.
+#[derive(Debug)]
+pub struct MemoryPipe {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    peer_identity: String,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl MemoryPipe {
+    pub fn pair() -> (MemoryPipe, MemoryPipe) {
+        let id: usize = NEXT_PIPE_ID.fetch_add(1, Ordering::Relaxed);
+        let there: Arc<Pipe> = Arc::new(Pipe::default());
+        let back: Arc<Pipe> = Arc::new(Pipe::default());
+        (
+            MemoryPipe {
+                incoming: back.clone(),
+                outgoing: there.clone(),
+                peer_identity: format!("memory:{}b", id),
+                read_timeout: Mutex::new(None),
+            },
+            MemoryPipe {
+                incoming: there,
+                outgoing: back,
+                peer_identity: format!("memory:{}a", id),
+                read_timeout: Mutex::new(None),
+            },
+        )
+    }
+}
+
+impl Read for MemoryPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout: Option<Duration> = *self.read_timeout.lock().unwrap();
+        let deadline: Option<Instant> = timeout.map(|timeout| Instant::now() + timeout);
+        let mut buffer: MutexGuard<PipeBuffer> = self.incoming.buffer.lock().unwrap();
+        while buffer.bytes.is_empty() && !buffer.closed {
+            buffer = match deadline {
+                Some(deadline) => {
+                    let now: Instant = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(ErrorKind::WouldBlock, "read timed out"));
+                    }
+                    self.incoming
+                        .readable
+                        .wait_timeout(buffer, deadline - now)
+                        .unwrap()
+                        .0
+                }
+                None => self.incoming.readable.wait(buffer).unwrap(),
+            };
+        }
+        let len: usize = buf.len().min(buffer.bytes.len());
+        for (byte, slot) in buffer.bytes.drain(..len).zip(buf.iter_mut()) {
+            *slot = byte;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MemoryPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut buffer: MutexGuard<PipeBuffer> = self.outgoing.buffer.lock().unwrap();
+        if buffer.closed {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "pipe closed"));
+        }
+        buffer.bytes.extend(buf);
+        self.outgoing.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryPipe {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    // Writes never block, so there is nothing to time out
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn peer_identity(&self) -> String {
+        self.peer_identity.clone()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.incoming.close();
+        self.outgoing.close();
+        Ok(())
+    }
+}
+
+impl Drop for MemoryPipe {
+    fn drop(&mut self) {
+        let _ = Transport::shutdown(self);
+    }
+}
+
+// Accepts MemoryPipes handed over by its MemoryConnectors
+#[derive(Debug)]
+pub struct MemoryListener {
+    incoming: Receiver<MemoryPipe>,
+    address: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryConnector {
+    listener: Sender<MemoryPipe>,
+}
+
+impl MemoryListener {
+    pub fn new(address: &str) -> (MemoryListener, MemoryConnector) {
+        let (sender, receiver) = mpsc::channel();
+        (
+            MemoryListener {
+                incoming: receiver,
+                address: address.to_string(),
+            },
+            MemoryConnector { listener: sender },
+        )
+    }
+}
+
+impl Listener for MemoryListener {
+    // Fails with NotConnected once every connector is gone, as nothing can ever arrive
+    fn accept(&self) -> io::Result<Box<dyn Transport>> {
+        match self.incoming.recv() {
+            Ok(pipe) => Ok(Box::new(pipe)),
+            Err(_) => Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "no connectors are left",
+            )),
+        }
+    }
+
+    fn local_address(&self) -> io::Result<String> {
+        Ok(self.address.clone())
+    }
+}
+
+impl MemoryConnector {
+    pub fn connect(&self) -> io::Result<MemoryPipe> {
+        let (ours, theirs) = MemoryPipe::pair();
+        self.listener
+            .send(theirs)
+            .map_err(|_| io::Error::new(ErrorKind::ConnectionRefused, "listener is gone"))?;
+        Ok(ours)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FramedStream, ProtocolError};
+    use std::thread;
+
+    #[test]
+    fn memory_pipes_carry_frames_both_ways() {
+        let (listener, connector) = MemoryListener::new("memory:test");
+        let client = thread::spawn(move || {
+            let mut stream: FramedStream<MemoryPipe> =
+                FramedStream::new(connector.connect().unwrap());
+            stream.write_frame(b"ping").unwrap();
+            stream.read_frame().unwrap()
+        });
+        let mut accepted: FramedStream<Box<dyn Transport>> =
+            FramedStream::new(listener.accept().unwrap());
+        assert_eq!(accepted.read_frame().unwrap(), b"ping");
+        accepted.write_frame(b"pong").unwrap();
+        assert_eq!(client.join().unwrap(), b"pong");
+        // Every connector is gone, so nothing more can be accepted
+        assert_eq!(
+            listener.accept().unwrap_err().kind(),
+            ErrorKind::NotConnected
+        );
+    }
+
+    #[test]
+    fn addresses_select_the_transport() {
+        let path: std::path::PathBuf =
+            std::env::temp_dir().join(format!("crush-transport-{}.sock", std::process::id()));
+        let address: String = format!("unix:{}", path.display());
+        let listener: Box<dyn Listener> = bind(&address).unwrap();
+        assert_eq!(listener.local_address().unwrap(), address);
+        let mut client: FramedStream<Box<dyn Transport>> =
+            FramedStream::new(connect(&address).unwrap());
+        let mut accepted: FramedStream<Box<dyn Transport>> =
+            FramedStream::new(listener.accept().unwrap());
+        client.write_frame(b"over a Unix socket").unwrap();
+        assert_eq!(accepted.read_frame().unwrap(), b"over a Unix socket");
+        // Nobody else can take over the address while it is in use
+        assert_eq!(bind(&address).err().unwrap().kind(), ErrorKind::AddrInUse);
+        drop(listener);
+        // The stale socket is replaced rather than stopping the next bind
+        assert!(bind(&address).is_ok());
+        fs::remove_file(&path).unwrap();
+        let listener: Box<dyn Listener> = bind("127.0.0.1:0").unwrap();
+        let mut client: FramedStream<Box<dyn Transport>> =
+            FramedStream::new(connect(&listener.local_address().unwrap()).unwrap());
+        let mut accepted: FramedStream<Box<dyn Transport>> =
+            FramedStream::new(listener.accept().unwrap());
+        client.write_frame(b"over TCP").unwrap();
+        assert_eq!(accepted.read_frame().unwrap(), b"over TCP");
+    }
+
+    #[test]
+    fn memory_pipes_time_out_and_close() {
+        let (ours, theirs) = MemoryPipe::pair();
+        let mut ours: FramedStream<MemoryPipe> = FramedStream::new(ours);
+        ours.get_ref()
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        assert!(matches!(ours.read_frame(), Err(ProtocolError::Timeout)));
+        let mut theirs: FramedStream<MemoryPipe> = FramedStream::new(theirs);
+        theirs.write_frame(b"last words").unwrap();
+        drop(theirs);
+        // Anything written before the other end went away is still delivered
+        assert_eq!(ours.read_frame().unwrap(), b"last words");
+        assert!(matches!(ours.read_frame(), Err(ProtocolError::Closed)));
+        assert!(matches!(
+            ours.write_frame(b"anyone there?"),
+            Err(ProtocolError::Closed)
+        ));
+    }
+}
@@ -0,0 +1,584 @@
+// Copyright (C) 2024  Eshe
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// WebSockets (RFC 6455) as a transport, so browsers can connect, selected by putting ws: in front
+// of any other address (e.g. ws:127.0.0.1:6680).
+// The connection carries exactly the same bytes as a TCP one, the usual handshake and message
+// records, in binary WebSocket messages. Messages are only a way of getting bytes through, so a
+// browser is free to split or join frames across them however it likes. Text messages are refused.
+
+use crate::transport::{Listener, Transport, ACCEPT_HANDSHAKE_TIMEOUT};
+use crate::PEER_LINK_MAX_FRAME_LEN;
+use openssl::base64::encode_block;
+use openssl::rand::rand_bytes;
+use openssl::sha::sha1;
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::Duration;
+
+pub const WEBSOCKET_SCHEME: &str = "ws:";
+
+// Appended to the client's key to prove the server understood the upgrade
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE_LEN: usize = 8192;
+// Room for the largest protocol frame in a single message
+const MAX_MESSAGE_LEN: usize = PEER_LINK_MAX_FRAME_LEN + 1024;
+const MAX_CONTROL_PAYLOAD_LEN: u64 = 125;
+const READ_CHUNK_LEN: usize = 4096;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, reason.to_string())
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+fn accept_key(key: &str) -> String {
+    encode_block(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+// One frame off the wire, already unmasked
+struct Frame {
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// Like the other transports, reads which time out part way through a frame keep what they have,
+// and writes which time out part way through are finished before anything else is sent.
+#[derive(Debug)]
+pub struct WebSocketStream {
+    inner: Box<dyn Transport>,
+    // Set on the connecting end, which has to mask everything it sends
+    masking: bool,
+    // Bytes read but not yet parsed into frames
+    raw: Vec<u8>,
+    // Data from binary messages not yet read
+    payload: Vec<u8>,
+    // Encoded frames not yet fully written
+    pending: Vec<u8>,
+    closed: bool,
+}
+
+impl WebSocketStream {
+    fn new(inner: Box<dyn Transport>, masking: bool, raw: Vec<u8>) -> Self {
+        WebSocketStream {
+            inner,
+            masking,
+            raw,
+            payload: Vec::new(),
+            pending: Vec::new(),
+            closed: false,
+        }
+    }
+
+    fn encode_frame(&self, opcode: u8, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let mut frame: Vec<u8> = Vec::with_capacity(14 + payload.len());
+        frame.push(0x80 | opcode);
+        let mask_bit: u8 = if self.masking { 0x80 } else { 0 };
+        if payload.len() < 126 {
+            frame.push(mask_bit | payload.len() as u8);
+        } else if let Ok(len) = u16::try_from(payload.len()) {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&len.to_be_bytes());
+        } else {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+        if self.masking {
+            let mut mask: [u8; 4] = [0; 4];
+            rand_bytes(&mut mask).map_err(io::Error::other)?;
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        } else {
+            frame.extend_from_slice(payload);
+        }
+        Ok(frame)
+    }
+
+    fn take_frame(&mut self) -> io::Result<Option<Frame>> {
+        if self.raw.len() < 2 {
+            return Ok(None);
+        }
+        if self.raw[0] & 0x70 != 0 {
+            return Err(invalid_data("WebSocket extensions are not supported"));
+        }
+        let fin: bool = self.raw[0] & 0x80 != 0;
+        let opcode: u8 = self.raw[0] & 0x0F;
+        let masked: bool = self.raw[1] & 0x80 != 0;
+        // Clients always mask and servers never do
+        if masked == self.masking {
+            return Err(invalid_data("WebSocket frame masked the wrong way"));
+        }
+        let (len, mut offset): (u64, usize) = match self.raw[1] & 0x7F {
+            126 if self.raw.len() >= 4 => {
+                (u16::from_be_bytes([self.raw[2], self.raw[3]]) as u64, 4)
+            }
+            127 if self.raw.len() >= 10 => {
+                let mut length_bytes: [u8; 8] = [0; 8];
+                length_bytes.copy_from_slice(&self.raw[2..10]);
+                (u64::from_be_bytes(length_bytes), 10)
+            }
+            126 | 127 => return Ok(None),
+            len => (len as u64, 2),
+        };
+        if len > MAX_MESSAGE_LEN as u64 {
+            return Err(invalid_data("WebSocket frame is too large"));
+        }
+        // Control frames can't be fragmented and carry at most 125 bytes (RFC 6455 section 5.5)
+        if opcode & 0x08 != 0 && (!fin || len > MAX_CONTROL_PAYLOAD_LEN) {
+            return Err(invalid_data(
+                "WebSocket control frame is fragmented or too large",
+            ));
+        }
+        let len: usize = len as usize;
+        let mask_len: usize = if masked { 4 } else { 0 };
+        if self.raw.len() < offset + mask_len + len {
+            return Ok(None);
+        }
+        let mut mask: [u8; 4] = [0; 4];
+        if masked {
+            mask.copy_from_slice(&self.raw[offset..offset + 4]);
+            offset += 4;
+        }
+        let payload: Vec<u8> = self.raw[offset..offset + len]
+            .iter()
+            .zip(mask.iter().cycle())
+            .map(|(b, m)| b ^ m)
+            .collect();
+        self.raw.drain(..offset + len);
+        Ok(Some(Frame { opcode, payload }))
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> io::Result<()> {
+        match frame.opcode {
+            OPCODE_BINARY | OPCODE_CONTINUATION => self.payload.extend_from_slice(&frame.payload),
+            OPCODE_TEXT => return Err(invalid_data("only binary WebSocket messages are carried")),
+            OPCODE_CLOSE => {
+                // Echo the status code back, then there is nothing more to read
+                let reply: Vec<u8> =
+                    self.encode_frame(OPCODE_CLOSE, &frame.payload[..frame.payload.len().min(2)])?;
+                self.pending.extend_from_slice(&reply);
+                let _ = self.send_pending();
+                self.closed = true;
+            }
+            OPCODE_PING => {
+                let reply: Vec<u8> = self.encode_frame(OPCODE_PONG, &frame.payload)?;
+                self.pending.extend_from_slice(&reply);
+                if let Err(err) = self.send_pending() {
+                    if !is_timeout(&err) {
+                        return Err(err);
+                    }
+                }
+            }
+            OPCODE_PONG => {}
+            _ => return Err(invalid_data("unknown WebSocket opcode")),
+        }
+        Ok(())
+    }
+
+    fn send_pending(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.inner.write(&self.pending) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for WebSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.payload.is_empty() {
+                let len: usize = buf.len().min(self.payload.len());
+                buf[..len].copy_from_slice(&self.payload[..len]);
+                self.payload.drain(..len);
+                return Ok(len);
+            }
+            if self.closed {
+                return Ok(0);
+            }
+            if let Some(frame) = self.take_frame()? {
+                self.handle_frame(frame)?;
+                continue;
+            }
+            let mut chunk: [u8; READ_CHUNK_LEN] = [0; READ_CHUNK_LEN];
+            match self.inner.read(&mut chunk)? {
+                0 => {
+                    self.closed = true;
+                    return Ok(0);
+                }
+                n => self.raw.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+}
+
+impl Write for WebSocketStream {
+    // Each write is sent as one binary message
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        // Nothing of buf is taken until earlier frames are out of the way
+        self.send_pending()?;
+        let frame: Vec<u8> = self.encode_frame(OPCODE_BINARY, buf)?;
+        self.pending = frame;
+        match self.send_pending() {
+            Err(err) if !is_timeout(&err) => Err(err),
+            // Once queued the rest of the frame goes out ahead of the next one
+            _ => Ok(buf.len()),
+        }
+    }
+
+    // A frame still queued after a timeout stays queued, and goes out ahead of the next one
+    fn flush(&mut self) -> io::Result<()> {
+        match self.send_pending() {
+            Err(err) if !is_timeout(&err) => Err(err),
+            _ => self.inner.flush(),
+        }
+    }
+}
+
+impl Transport for WebSocketStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn peer_identity(&self) -> String {
+        format!("{}{}", WEBSOCKET_SCHEME, self.inner.peer_identity())
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+}
+
+// Reads an HTTP request or response head, returning its lines and anything sent after it
+fn read_http_head(transport: &mut Box<dyn Transport>) -> io::Result<(Vec<String>, Vec<u8>)> {
+    let mut head: Vec<u8> = Vec::new();
+    loop {
+        if let Some(end) = head.windows(4).position(|window| window == b"\r\n\r\n") {
+            let rest: Vec<u8> = head.split_off(end + 4);
+            let text: String = String::from_utf8(head)
+                .map_err(|_| invalid_data("WebSocket handshake is not UTF-8"))?;
+            return Ok((text.lines().map(str::to_string).collect(), rest));
+        }
+        if head.len() > MAX_HANDSHAKE_LEN {
+            return Err(invalid_data("WebSocket handshake is too long"));
+        }
+        let mut chunk: [u8; 1024] = [0; 1024];
+        match transport.read(&mut chunk)? {
+            0 => return Err(ErrorKind::UnexpectedEof.into()),
+            n => head.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+// Finds a header's value, header names are case-insensitive
+fn header<'a>(lines: &'a [String], name: &str) -> Option<&'a str> {
+    lines.iter().skip(1).find_map(|line| {
+        let (line_name, value) = line.split_once(':')?;
+        line_name
+            .trim()
+            .eq_ignore_ascii_case(name)
+            .then_some(value.trim())
+    })
+}
+
+// Answers the upgrade request from a newly connected client
+pub fn accept(mut transport: Box<dyn Transport>) -> io::Result<WebSocketStream> {
+    let (lines, rest) = read_http_head(&mut transport)?;
+    let is_upgrade: bool = lines.first().is_some_and(|line| line.starts_with("GET "))
+        && header(&lines, "upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+        && header(&lines, "connection").is_some_and(|value| {
+            value
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+        })
+        && header(&lines, "sec-websocket-version") == Some("13");
+    let key: &str = match header(&lines, "sec-websocket-key") {
+        Some(key) if is_upgrade => key,
+        _ => {
+            let _ = transport.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n");
+            return Err(invalid_data("not a WebSocket upgrade request"));
+        }
+    };
+    let response: String = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    transport.write_all(response.as_bytes())?;
+    Ok(WebSocketStream::new(transport, false, rest))
+}
+
+// Upgrades a connection to a WebSocket server, host is sent as the Host header
+pub fn connect(mut transport: Box<dyn Transport>, host: &str) -> io::Result<WebSocketStream> {
+    let mut key_bytes: [u8; 16] = [0; 16];
+    rand_bytes(&mut key_bytes).map_err(io::Error::other)?;
+    let key: String = encode_block(&key_bytes);
+    let request: String = format!(
+        "GET / HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        host, key
+    );
+    transport.write_all(request.as_bytes())?;
+    let (lines, rest) = read_http_head(&mut transport)?;
+    let switched: bool = lines
+        .first()
+        .is_some_and(|line| line.split_whitespace().nth(1) == Some("101"));
+    if !switched || header(&lines, "sec-websocket-accept") != Some(accept_key(&key).as_str()) {
+        return Err(invalid_data("server did not accept the WebSocket upgrade"));
+    }
+    Ok(WebSocketStream::new(transport, true, rest))
+}
+
+// Hands out connections which have completed the WebSocket upgrade
+pub struct WebSocketListener {
+    inner: Box<dyn Listener>,
+    handshake_timeout: Duration,
+}
+
+impl WebSocketListener {
+    pub fn new(inner: Box<dyn Listener>) -> Self {
+        WebSocketListener {
+            inner,
+            handshake_timeout: ACCEPT_HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+}
+
+impl Listener for WebSocketListener {
+    // A connection which fails the upgrade, or doesn't finish it in time, is reported as an error
+    // and the listener carries on
+    fn accept(&self) -> io::Result<Box<dyn Transport>> {
+        let transport: Box<dyn Transport> = self.inner.accept()?;
+        transport.set_read_timeout(Some(self.handshake_timeout))?;
+        transport.set_write_timeout(Some(self.handshake_timeout))?;
+        let stream: WebSocketStream = accept(transport).map_err(|err| {
+            if is_timeout(&err) {
+                io::Error::new(
+                    ErrorKind::ConnectionAborted,
+                    "timed out waiting for the WebSocket upgrade",
+                )
+            } else {
+                err
+            }
+        })?;
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(Box::new(stream))
+    }
+
+    fn local_address(&self) -> io::Result<String> {
+        Ok(format!(
+            "{}{}",
+            WEBSOCKET_SCHEME,
+            self.inner.local_address()?
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FramedStream, MemoryListener, MemoryPipe, ProtocolError};
+    use std::thread;
+
+    // Masks a frame the way a browser would
+    fn client_frame(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+        let mask: [u8; 4] = [1, 2, 3, 4];
+        let mut frame: Vec<u8> = vec![first_byte];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        frame
+    }
+
+    #[test]
+    fn accept_keys_follow_the_rfc() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn frames_pass_through_websockets() {
+        let (listener, connector) = MemoryListener::new("memory:ws");
+        let listener: WebSocketListener = WebSocketListener::new(Box::new(listener));
+        assert_eq!(listener.local_address().unwrap(), "ws:memory:ws");
+        let client = thread::spawn(move || {
+            let stream: WebSocketStream =
+                connect(Box::new(connector.connect().unwrap()), "localhost").unwrap();
+            let mut stream: FramedStream<WebSocketStream> = FramedStream::new(stream);
+            stream.write_frame(b"ping").unwrap();
+            stream.read_frame().unwrap()
+        });
+        let mut accepted: FramedStream<Box<dyn Transport>> =
+            FramedStream::new(listener.accept().unwrap());
+        assert_eq!(accepted.read_frame().unwrap(), b"ping");
+        accepted.write_frame(b"pong").unwrap();
+        assert_eq!(client.join().unwrap(), b"pong");
+    }
+
+    #[test]
+    fn browser_messages_are_joined_back_into_frames() {
+        let (mut browser, server) = MemoryPipe::pair();
+        browser
+            .write_all(
+                b"GET /crush HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                  Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+        // A protocol frame split over a fragmented message, with a ping in the middle
+        browser
+            .write_all(&client_frame(OPCODE_BINARY, &[0, 0, 0, 5, b'h']))
+            .unwrap();
+        browser
+            .write_all(&client_frame(0x80 | OPCODE_PING, b"still there?"))
+            .unwrap();
+        browser
+            .write_all(&client_frame(0x80 | OPCODE_CONTINUATION, b"ello"))
+            .unwrap();
+        let mut accepted: FramedStream<WebSocketStream> =
+            FramedStream::new(accept(Box::new(server)).unwrap());
+        assert_eq!(accepted.read_frame().unwrap(), b"hello");
+        let mut response: Vec<u8> = vec![0; 4096];
+        let len: usize = browser.read(&mut response).unwrap();
+        let mut pong: Vec<u8> = vec![0x80 | OPCODE_PONG, 12];
+        pong.extend_from_slice(b"still there?");
+        assert!(response[..len].ends_with(&pong));
+        let response: String = String::from_utf8_lossy(&response[..len - pong.len()]).to_string();
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        // Text is refused
+        browser
+            .write_all(&client_frame(0x80 | OPCODE_TEXT, b"{}"))
+            .unwrap();
+        assert!(matches!(accepted.read_frame(), Err(ProtocolError::Io(_))));
+    }
+
+    // Opens a WebSocket from the browser's end, without reading the server's response
+    fn browser_upgrade() -> (MemoryPipe, WebSocketStream) {
+        let (mut browser, server) = MemoryPipe::pair();
+        browser
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                  Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+        let accepted: WebSocketStream = accept(Box::new(server)).unwrap();
+        (browser, accepted)
+    }
+
+    #[test]
+    fn control_frames_must_be_short_and_whole() {
+        let mut buf: [u8; 16] = [0; 16];
+        // Without FIN
+        let (mut browser, mut accepted) = browser_upgrade();
+        browser
+            .write_all(&client_frame(OPCODE_PING, b"part"))
+            .unwrap();
+        assert_eq!(
+            accepted.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        // Over 125 bytes
+        let (mut browser, mut accepted) = browser_upgrade();
+        browser
+            .write_all(&client_frame(0x80 | OPCODE_PING, &[0; 126]))
+            .unwrap();
+        assert_eq!(
+            accepted.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        // 125 bytes is still fine
+        let (mut browser, mut accepted) = browser_upgrade();
+        browser
+            .write_all(&client_frame(0x80 | OPCODE_PING, &[0; 125]))
+            .unwrap();
+        browser
+            .write_all(&client_frame(0x80 | OPCODE_BINARY, b"data"))
+            .unwrap();
+        assert_eq!(accepted.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"data");
+    }
+
+    #[test]
+    fn quiet_connections_do_not_hold_up_the_listener() {
+        let (listener, connector) = MemoryListener::new("memory:ws");
+        let mut listener: WebSocketListener = WebSocketListener::new(Box::new(listener));
+        listener.set_handshake_timeout(Duration::from_millis(50));
+        // Connects and never says anything
+        let _silent = connector.connect().unwrap();
+        assert_eq!(
+            listener.accept().unwrap_err().kind(),
+            ErrorKind::ConnectionAborted
+        );
+        // The listener carries on with the next connection
+        listener.set_handshake_timeout(ACCEPT_HANDSHAKE_TIMEOUT);
+        let client = thread::spawn(move || {
+            let stream: WebSocketStream =
+                connect(Box::new(connector.connect().unwrap()), "localhost").unwrap();
+            let mut stream: FramedStream<WebSocketStream> = FramedStream::new(stream);
+            stream.write_frame(b"late").unwrap();
+            stream.read_frame().unwrap()
+        });
+        let mut accepted: FramedStream<Box<dyn Transport>> =
+            FramedStream::new(listener.accept().unwrap());
+        assert_eq!(accepted.read_frame().unwrap(), b"late");
+        accepted.write_frame(b"ok").unwrap();
+        assert_eq!(client.join().unwrap(), b"ok");
+    }
+
+    #[test]
+    fn plain_http_is_turned_away() {
+        let (mut browser, server) = MemoryPipe::pair();
+        browser
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        assert!(accept(Box::new(server)).is_err());
+        let mut response: Vec<u8> = vec![0; 4096];
+        let len: usize = browser.read(&mut response).unwrap();
+        assert!(response[..len].starts_with(b"HTTP/1.1 400 "));
+    }
+}
@@ -14,71 +14,34 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use openssl::pkey::{Private, Public};
-use openssl::rand::rand_bytes;
 use openssl::rsa::Rsa;
-use std::collections::VecDeque;
-use std::io::Read;
-use std::net::TcpStream;
 use std::sync::{Arc, Mutex};
-use utils::{decrypt_rsa, receive_message, send_message, Message};
+use utils::transport::ACCEPT_HANDSHAKE_TIMEOUT;
+use utils::{FramedStream, ProtocolError, SecureChannel, Transport, SERVER_LINK_MAX_FRAME_LEN};
 
 #[derive(Debug)]
-pub enum Event {
-    NewClient(Arc<Mutex<Client>>),
-    ClientDisconnected(Arc<Mutex<Client>>),
+pub enum Event<T: Transport = Box<dyn Transport>> {
+    NewClient(Arc<Mutex<Client<T>>>),
+    ClientDisconnected(Arc<Mutex<Client<T>>>),
 }
 
+// Connected over whatever a listener hands out, or anything else carrying bytes
 #[derive(Debug)]
-pub struct Client {
-    pub tcp_stream: TcpStream,
-    pub aes_key: [u8; 32],
-    pub tag: [u8; 16],
-    pub events: Arc<Mutex<VecDeque<Event>>>,
-    pub to_send: Arc<Mutex<VecDeque<Message>>>,
+pub struct Client<T: Transport = Box<dyn Transport>> {
+    pub channel: SecureChannel<T>,
     pub public_key: Option<Rsa<Public>>,
     pub server_address: Option<String>,
 }
 
-impl Client {
-    pub fn new(
-        mut tcp_stream: TcpStream,
-        key: &Rsa<Private>,
-        events: Arc<Mutex<VecDeque<Event>>>,
-        to_send: Arc<Mutex<VecDeque<Message>>>,
-    ) -> Client {
-        let mut encrypted_aes: [u8; 256] = [0; 256];
-        let mut tag: [u8; 16] = [0; 16];
-        rand_bytes(&mut tag).unwrap();
-        tcp_stream
-            .read(&mut encrypted_aes)
-            .expect("256 bytes of RSA encrypted data");
-        let aes_key_decrypted: Vec<u8> = decrypt_rsa(&encrypted_aes, key);
-        let mut aes_key: [u8; 32] = [0; 32];
-        for i in 0..32 {
-            aes_key[i] = aes_key_decrypted[i];
-        }
-        let new_client = Client {
-            tcp_stream,
-            aes_key,
-            tag,
-            events,
-            to_send,
+impl<T: Transport> Client<T> {
+    // Runs the handshake on the accept thread, so it is given up on if the client goes quiet
+    pub fn new(transport: T, key: &Rsa<Private>) -> Result<Client<T>, ProtocolError> {
+        let stream: FramedStream<T> =
+            FramedStream::with_max_frame_len(transport, SERVER_LINK_MAX_FRAME_LEN);
+        Ok(Client {
+            channel: SecureChannel::respond_within(stream, key, ACCEPT_HANDSHAKE_TIMEOUT)?,
             public_key: None,
             server_address: None,
-        };
-        new_client
-    }
-
-    pub fn send_message(&mut self, message: Message) -> Result<(), String> {
-        send_message(
-            message,
-            &mut self.tcp_stream,
-            &mut self.aes_key,
-            &mut self.tag,
-        )
-    }
-
-    pub fn receive_message(&mut self) -> Option<Message> {
-        receive_message(&mut self.tcp_stream, &self.aes_key)
+        })
     }
 }
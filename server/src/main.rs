@@ -14,16 +14,33 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::collections::{HashMap, VecDeque};
-use std::net::TcpListener;
+use std::env;
+use std::path::Path;
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
 use std::thread::sleep;
-use std::time::{self, Duration};
+use std::time::{self, Duration, Instant};
 mod clients;
 use clients::*;
 use openssl::pkey::Private;
 use openssl::rsa::Rsa;
-use utils::{get_rsa_private_key, Message, MessageType};
+use utils::payloads::{
+    AddPeer, Goodbye, GoodbyeReason, InformAddress, InformPublicKey, Payload, RemovePeer, Secret,
+};
+use utils::tls::{self, TlsIdentity};
+use utils::transport::{Listener, Transport};
+use utils::{
+    get_rsa_private_key, Heartbeat, HeartbeatConfig, Message, MessageType, ProtocolError,
+    RekeyPolicy,
+};
+
+// Secrets which have been sent by one client, waiting for another client to send the same one
+type PendingSecrets<T = Box<dyn Transport>> = HashMap<Vec<u8>, Arc<Mutex<Client<T>>>>;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:6666";
+// Presented on TLS addresses if present, otherwise a self-signed certificate is made for server.priv
+const SERVER_CERTIFICATE: &str = "server.crt";
 
 // The entrypoint for the thread which constantly sends messages to clients
 fn send_to_clients(
@@ -33,10 +50,11 @@ fn send_to_clients(
 ) {
     loop {
         sleep(time::Duration::from_millis(250));
+        let mut disconnected: Vec<Arc<Mutex<Client>>> = Vec::new();
         {
             let mut to_send_deque: MutexGuard<VecDeque<Message>> = to_send.lock().unwrap();
             let mut has_succeeded: bool = true;
-            if to_send_deque.len() > 0 {
+            if !to_send_deque.is_empty() {
                 println!("Trying to send message...");
                 println!("{:?}", all_clients.lock().unwrap().first());
                 for client in all_clients.lock().unwrap().iter() {
@@ -50,35 +68,27 @@ fn send_to_clients(
                         }
                     };
                     println!("Client lock obtained");
-                    match message.message_type {
-                        // If the message is informing clients of a new peer,
-                        MessageType::AddPeer => {
-                            // Then there is no need to inform the new peer
-                            if client_guard.server_address.is_none() {
-                                println!("Not informing due to server address");
-                                has_succeeded = false;
-                                continue;
-                            }
-                            if client_guard.server_address.clone().unwrap()
-                                == String::from_utf8(message.content.clone())
-                                    .unwrap()
-                                    .split_terminator(",")
-                                    .collect::<Vec<&str>>()[0]
-                            {
-                                continue;
-                            }
-                            println!("Informing client of new peer...");
+                    // If the message is informing clients of a new peer,
+                    if let MessageType::AddPeer = message.message_type {
+                        // Then there is no need to inform the new peer
+                        if client_guard.server_address.is_none() {
+                            println!("Not informing due to server address");
+                            has_succeeded = false;
+                            continue;
+                        }
+                        let new_peer_address: Option<String> = AddPeer::from_message(&message)
+                            .ok()
+                            .map(|add_peer| add_peer.address);
+                        if client_guard.server_address == new_peer_address {
+                            continue;
                         }
-                        _ => {}
+                        println!("Informing client of new peer...");
                     }
-                    match client_guard.send_message(message) {
+                    match client_guard.channel.send_reliable(message) {
                         Ok(_) => {}
                         Err(err) => {
                             println!("{}", err);
-                            events
-                                .lock()
-                                .unwrap()
-                                .push_back(Event::ClientDisconnected(client.clone()));
+                            disconnected.push(client.clone());
                         }
                     }
                 }
@@ -87,6 +97,13 @@ fn send_to_clients(
                 }
             }
         }
+        // Only reported once the clients are let go of, as handling the events needs them
+        for client in disconnected {
+            events
+                .lock()
+                .unwrap()
+                .push_back(Event::ClientDisconnected(client));
+        }
     }
 }
 
@@ -94,15 +111,26 @@ fn handle_events(
     all_clients: Arc<Mutex<Vec<Arc<Mutex<Client>>>>>,
     to_send: Arc<Mutex<VecDeque<Message>>>,
     events: Arc<Mutex<VecDeque<Event>>>,
+    user_crush_client: Arc<Mutex<PendingSecrets>>,
 ) {
     loop {
         sleep(time::Duration::from_millis(260));
         {
             let mut events_deque: MutexGuard<VecDeque<Event>> = events.lock().unwrap();
-            if events_deque.len() > 0 {
+            if !events_deque.is_empty() {
                 println!("Handling event...");
                 match events_deque.front().unwrap() {
                     Event::NewClient(client) => {
+                        // A client which left before it was announced never will be
+                        let still_connected: bool = all_clients
+                            .lock()
+                            .unwrap()
+                            .iter()
+                            .any(|x| Arc::ptr_eq(x, client));
+                        if !still_connected {
+                            events_deque.pop_front();
+                            continue;
+                        }
                         // Wait for the client to tell us their RSA key as well as their listener's address, as long as waiting for all previous messages
                         // to be handled
                         let client_lock: MutexGuard<Client> = client.lock().unwrap();
@@ -113,53 +141,47 @@ fn handle_events(
                             };
                         if client_lock.public_key.is_none()
                             || client_lock.server_address.is_none()
-                            || to_send_lock.len() > 0
+                            || !to_send_lock.is_empty()
                         {
                             continue;
                         }
-                        let mut message_content: String;
-                        message_content = client_lock.server_address.clone().unwrap();
-                        message_content = message_content
-                            + ","
-                            + &String::from_utf8(
-                                client_lock
-                                    .public_key
-                                    .clone()
-                                    .unwrap()
-                                    .public_key_to_pem()
-                                    .unwrap(),
-                            )
-                            .unwrap();
-                        to_send_lock.push_back(Message::new(
-                            message_content.as_bytes().to_vec(),
-                            MessageType::AddPeer,
-                        ));
+                        let add_peer: AddPeer = AddPeer {
+                            address: client_lock.server_address.clone().unwrap(),
+                            public_key: client_lock.public_key.clone().unwrap(),
+                        };
+                        to_send_lock.push_back(add_peer.to_message());
                     }
                     Event::ClientDisconnected(client) => {
                         println!("Client disconnected");
-                        let client_index: usize = all_clients
-                            .lock()
-                            .unwrap()
-                            .iter()
-                            .position(|x| {
-                                x.lock().unwrap().aes_key == client.lock().unwrap().aes_key
-                            })
-                            .unwrap();
-                        all_clients.lock().unwrap().remove(client_index);
-                        // Inform the clients that a peer should be removed
-                        to_send.lock().unwrap().push_back(Message::new(
-                            client
+                        // The same client may be reported more than once, if several sends failed
+                        let was_connected: bool = {
+                            let mut all_clients_lock: MutexGuard<Vec<Arc<Mutex<Client>>>> =
+                                all_clients.lock().unwrap();
+                            let client_count: usize = all_clients_lock.len();
+                            all_clients_lock.retain(|x| !Arc::ptr_eq(x, client));
+                            all_clients_lock.len() != client_count
+                        };
+                        if was_connected {
+                            // Nobody can match with a client which has gone
+                            user_crush_client
                                 .lock()
                                 .unwrap()
-                                .tcp_stream
-                                .peer_addr()
-                                .unwrap()
-                                .ip()
-                                .to_string()
-                                .as_bytes()
-                                .to_vec(),
-                            MessageType::RemovePeer,
-                        ));
+                                .retain(|_, waiting| !Arc::ptr_eq(waiting, client));
+                            let client_lock: MutexGuard<Client> = client.lock().unwrap();
+                            // Inform the clients that a peer should be removed, if they were
+                            // ever told about it
+                            if let (Some(address), Some(public_key)) = (
+                                client_lock.server_address.clone(),
+                                client_lock.public_key.clone(),
+                            ) {
+                                let remove_peer: RemovePeer = RemovePeer {
+                                    address,
+                                    public_key,
+                                };
+                                drop(client_lock);
+                                to_send.lock().unwrap().push_back(remove_peer.to_message());
+                            }
+                        }
                     }
                 }
                 events_deque.pop_front();
@@ -168,78 +190,227 @@ fn handle_events(
     }
 }
 
-fn handle_client_messages(
-    client: Arc<Mutex<Client>>,
-    user_crush_client: Arc<Mutex<HashMap<String, Arc<Mutex<Client>>>>>,
+fn handle_client_messages<T: Transport>(
+    client: Arc<Mutex<Client<T>>>,
+    user_crush_client: Arc<Mutex<PendingSecrets<T>>>,
+    events: Arc<Mutex<VecDeque<Event<T>>>>,
+    heartbeat_config: HeartbeatConfig,
 ) {
+    let mut heartbeat: Heartbeat = Heartbeat::new(heartbeat_config, Instant::now());
+    let mut unmatched_secrets: Vec<Vec<u8>> = Vec::new();
     loop {
         {
-            let mut client_guarded: MutexGuard<Client> = client.lock().unwrap();
+            let mut client_guarded: MutexGuard<Client<T>> = client.lock().unwrap();
             client_guarded
-                .tcp_stream
+                .channel
+                .get_ref()
                 .set_read_timeout(Some(Duration::from_millis(200)))
                 .unwrap();
             client_guarded
-                .tcp_stream
+                .channel
+                .get_ref()
                 .set_write_timeout(Some(Duration::from_millis(400)))
                 .unwrap();
-            let received_message: Option<Message> = client_guarded.receive_message();
-            match received_message {
-                Some(x) => match x.message_type {
-                    MessageType::InformPublicKey => {
-                        client_guarded.public_key =
-                            Some(Rsa::public_key_from_pem(&x.content).unwrap());
+            // Check on the client before waiting for anything else from it
+            if heartbeat.timed_out(Instant::now()) {
+                println!("Client stopped responding");
+                let _ = client_guarded.channel.close(GoodbyeReason::TimedOut);
+                break;
+            }
+            if heartbeat.ping_due(Instant::now()) {
+                let ping: Message = heartbeat.ping(Instant::now());
+                if let Err(err) = client_guarded.channel.send(ping) {
+                    println!("Could not ping client: {}", err);
+                }
+            }
+            let received_message: Option<Message> = match client_guarded.channel.recv() {
+                Ok(value) => Some(value),
+                Err(ProtocolError::Timeout) => None,
+                Err(ProtocolError::Closed) => {
+                    println!("Client closed the connection");
+                    break;
+                }
+                Err(err @ ProtocolError::Oversized { .. }) => {
+                    println!("Dropping client: {}", err);
+                    let _ = client_guarded
+                        .channel
+                        .close(GoodbyeReason::ProtocolViolation);
+                    break;
+                }
+                Err(err) => {
+                    println!("Discarding message from client: {}", err);
+                    None
+                }
+            };
+            if let Some(x) = received_message {
+                heartbeat.received(Instant::now());
+                match x.message_type {
+                    MessageType::Ping => {
+                        if let Err(err) = client_guarded.channel.send(Heartbeat::pong(&x)) {
+                            println!("Could not answer ping from client: {}", err);
+                        }
                     }
-                    MessageType::InformAddress => {
-                        client_guarded.server_address = Some(String::from_utf8(x.content).unwrap());
+                    MessageType::Goodbye => {
+                        match Goodbye::from_message(&x) {
+                            Ok(goodbye) => println!("Client left: {}", goodbye.reason),
+                            Err(err) => println!("Client left with an invalid goodbye: {}", err),
+                        }
+                        let _ = client_guarded.channel.get_ref().shutdown();
+                        break;
                     }
+                    MessageType::InformPublicKey => match InformPublicKey::from_message(&x) {
+                        Ok(inform) => client_guarded.public_key = Some(inform.public_key),
+                        Err(err) => println!("Client sent an invalid public key: {}", err),
+                    },
+                    MessageType::InformAddress => match InformAddress::from_message(&x) {
+                        Ok(inform) => client_guarded.server_address = Some(inform.address),
+                        Err(err) => println!("Client sent an invalid address: {}", err),
+                    },
                     MessageType::Secret => {
                         println!("Secret obtained from client");
-                        let mut user_crush_lock: MutexGuard<HashMap<String, Arc<Mutex<Client>>>> =
-                            match user_crush_client.try_lock() {
-                                Ok(val) => val,
-                                Err(_) => continue,
-                            };
-                        let client_match =
-                            user_crush_lock.get(&String::from_utf8(x.content.clone()).unwrap());
-                        match client_match {
+                        let secret: Vec<u8> = match Secret::from_message(&x) {
+                            Ok(value) => value.secret,
+                            Err(err) => {
+                                println!("Client sent an invalid secret: {}", err);
+                                continue;
+                            }
+                        };
+                        unmatched_secrets.push(secret);
+                    }
+                    _ => {}
+                }
+            }
+            // The secret has been acknowledged, so it waits here until the pending secrets are
+            // free rather than being dropped
+            if !unmatched_secrets.is_empty() {
+                if let Ok(mut user_crush_lock) = user_crush_client.try_lock() {
+                    for secret in unmatched_secrets.drain(..) {
+                        match user_crush_lock.remove(&secret) {
                             Some(matched_client) => {
                                 let message: Message = Message::new(
                                     "MATCH OBTAINED".as_bytes().to_vec(),
                                     MessageType::DEBUG,
                                 );
-                                matched_client
+                                if let Err(err) = matched_client
                                     .lock()
                                     .unwrap()
-                                    .send_message(message.clone())
-                                    .unwrap();
-                                client_guarded.send_message(message).unwrap();
-                                user_crush_lock
-                                    .remove(&String::from_utf8(x.content.clone()).unwrap());
+                                    .channel
+                                    .send_reliable(message.clone())
+                                {
+                                    println!("Could not inform matched client: {}", err);
+                                }
+                                if let Err(err) = client_guarded.channel.send_reliable(message) {
+                                    println!("Could not inform client of match: {}", err);
+                                }
                             }
                             None => {
-                                user_crush_lock
-                                    .insert(String::from_utf8(x.content).unwrap(), client.clone());
+                                user_crush_lock.insert(secret, client.clone());
                             }
                         }
                     }
-                    _ => {}
-                },
-                None => {}
+                }
             }
         }
         sleep(Duration::from_millis(600));
     }
+    // The client is let go of by now, handling the event needs it too. Departures go to the front
+    // of the queue so they aren't held up behind clients which are still joining.
+    events
+        .lock()
+        .unwrap()
+        .push_front(Event::ClientDisconnected(client));
+}
+
+fn tls_identity(rsa_private_key: &Rsa<Private>) -> Result<TlsIdentity, ProtocolError> {
+    if Path::new(SERVER_CERTIFICATE).exists() {
+        TlsIdentity::from_pem_file(SERVER_CERTIFICATE, rsa_private_key)
+    } else {
+        TlsIdentity::self_signed(rsa_private_key)
+    }
+}
+
+// The entrypoint for a thread which accepts clients on one of the server's addresses
+fn accept_clients<L: Listener>(
+    listener: L,
+    rsa_private_key: Rsa<Private>,
+    all_clients: Arc<Mutex<Vec<Arc<Mutex<Client>>>>>,
+    events: Arc<Mutex<VecDeque<Event>>>,
+    user_crush_client: Arc<Mutex<PendingSecrets>>,
+    heartbeat_config: HeartbeatConfig,
+    rekey_policy: RekeyPolicy,
+) {
+    // On a client join,
+    loop {
+        let transport: Box<dyn Transport> = match listener.accept() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        let peer_identity: String = transport.peer_identity();
+        let mut new_client: Client = match Client::new(transport, &rsa_private_key) {
+            Ok(value) => value,
+            Err(err) => {
+                println!(
+                    "Handshake with new client {} failed: {}",
+                    peer_identity, err
+                );
+                continue;
+            }
+        };
+        new_client.channel.set_rekey_policy(rekey_policy);
+        let new_client_arc_mutex: Arc<Mutex<Client>> = Arc::new(Mutex::new(new_client));
+        // Spawn a new thread to handle the client's messages
+        {
+            let cloned_client = new_client_arc_mutex.clone();
+            let cloned_user_crush_client = user_crush_client.clone();
+            let cloned_events = events.clone();
+            thread::spawn(move || {
+                handle_client_messages(
+                    cloned_client,
+                    cloned_user_crush_client,
+                    cloned_events,
+                    heartbeat_config,
+                );
+            });
+        }
+        // And append the client to all_clients
+        all_clients
+            .lock()
+            .unwrap()
+            .push(new_client_arc_mutex.clone());
+        events
+            .lock()
+            .unwrap()
+            .push_back(Event::NewClient(new_client_arc_mutex));
+    }
 }
 
-fn main() -> std::io::Result<()> {
+// Usage: server [address...]
+// Listens on every address given (e.g. 127.0.0.1:6666, unix:/run/crush.sock, tls:127.0.0.1:6667
+// or ws:127.0.0.1:6680 for browsers), or on DEFAULT_ADDRESS if there are none.
+// Heartbeats follow CRUSH_HEARTBEAT_INTERVAL_MS and CRUSH_HEARTBEAT_TIMEOUT_MS if they are set.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let all_clients: Arc<Mutex<Vec<Arc<Mutex<Client>>>>> = Arc::new(Mutex::new(Vec::new()));
     let events: Arc<Mutex<VecDeque<Event>>> = Arc::new(Mutex::new(VecDeque::new()));
     let to_send_to_clients: Arc<Mutex<VecDeque<Message>>> = Arc::new(Mutex::new(VecDeque::new()));
-    let rsa_private_key: Rsa<Private> = get_rsa_private_key("server.priv");
-    let user_crush_client: Arc<Mutex<HashMap<String, Arc<Mutex<Client>>>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-    let server_socket: TcpListener = TcpListener::bind("127.0.0.1:6666")?;
+    let rsa_private_key: Rsa<Private> = match get_rsa_private_key("server.priv") {
+        Ok(value) => value,
+        Err(err) => {
+            eprintln!("Could not load server.priv: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let user_crush_client: Arc<Mutex<PendingSecrets>> = Arc::new(Mutex::new(HashMap::new()));
+    let heartbeat_config: HeartbeatConfig = HeartbeatConfig::from_env();
+    let rekey_policy: RekeyPolicy = RekeyPolicy::from_env();
+    let mut addresses: Vec<String> = env::args().skip(1).collect();
+    if addresses.is_empty() {
+        addresses.push(DEFAULT_ADDRESS.to_string());
+    }
+    let mut listeners: Vec<Box<dyn Listener>> = Vec::new();
+    for address in addresses {
+        listeners.push(tls::bind_to(&address, || tls_identity(&rsa_private_key))?);
+        println!("Listening on {}", address);
+    }
     // Spawn the thread which sends messages to clients
     {
         let cloned_to_send_to_clients = to_send_to_clients.clone();
@@ -254,41 +425,129 @@ fn main() -> std::io::Result<()> {
         let cloned_to_send_to_clients = to_send_to_clients.clone();
         let cloned_all_clients = all_clients.clone();
         let cloned_events = events.clone();
+        let cloned_user_crush_client = user_crush_client.clone();
         thread::spawn(move || {
-            handle_events(cloned_all_clients, cloned_to_send_to_clients, cloned_events);
+            handle_events(
+                cloned_all_clients,
+                cloned_to_send_to_clients,
+                cloned_events,
+                cloned_user_crush_client,
+            );
         });
     }
-    for incoming in server_socket.incoming() {
-        // On a client join,
-        match incoming {
-            Ok(stream) => {
-                let new_client: Client = Client::new(
-                    stream,
-                    &rsa_private_key,
-                    events.clone(),
-                    to_send_to_clients.clone(),
-                );
-                let new_client_arc_mutex: Arc<Mutex<Client>> = Arc::new(Mutex::new(new_client));
-                // Spawn a new thread to handle the client's messages
-                {
-                    let cloned_client = new_client_arc_mutex.clone();
-                    let cloned_user_crush_client = user_crush_client.clone();
-                    thread::spawn(move || {
-                        handle_client_messages(cloned_client, cloned_user_crush_client);
-                    });
-                }
-                // And append the client to all_clients
-                all_clients
-                    .lock()
-                    .unwrap()
-                    .push(new_client_arc_mutex.clone());
-                events
-                    .lock()
-                    .unwrap()
-                    .push_back(Event::NewClient(new_client_arc_mutex));
-            }
-            Err(_) => {}
+    // Spawn a thread accepting clients on each address
+    for listener in listeners {
+        let cloned_key = rsa_private_key.clone();
+        let cloned_all_clients = all_clients.clone();
+        let cloned_events = events.clone();
+        let cloned_user_crush_client = user_crush_client.clone();
+        thread::spawn(move || {
+            accept_clients(
+                listener,
+                cloned_key,
+                cloned_all_clients,
+                cloned_events,
+                cloned_user_crush_client,
+                heartbeat_config,
+                rekey_policy,
+            );
+        });
+    }
+    // Run until interrupted or terminated, then say goodbye to every client
+    let (shutdown_sender, shutdown_receiver) = mpsc::channel::<()>();
+    ctrlc::set_handler(move || {
+        let _ = shutdown_sender.send(());
+    })?;
+    let _ = shutdown_receiver.recv();
+    println!("Shutting down...");
+    let clients: Vec<Arc<Mutex<Client>>> = all_clients.lock().unwrap().clone();
+    for client in clients {
+        if let Err(err) = client
+            .lock()
+            .unwrap()
+            .channel
+            .close(GoodbyeReason::ShuttingDown)
+        {
+            println!("Could not say goodbye to client: {}", err);
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::Public;
+    use utils::{FramedStream, MemoryPipe, SecureChannel};
+
+    // A client connected over memory, along with the server's end of the connection
+    fn connect_client(key: &Rsa<Private>) -> (SecureChannel<MemoryPipe>, Client<MemoryPipe>) {
+        let public_key: Rsa<Public> =
+            Rsa::public_key_from_pem(&key.public_key_to_pem().unwrap()).unwrap();
+        let (ours, theirs) = MemoryPipe::pair();
+        let connecting = thread::spawn(move || {
+            SecureChannel::initiate(FramedStream::new(ours), &public_key).unwrap()
+        });
+        let client: Client<MemoryPipe> = Client::new(theirs, key).unwrap();
+        (connecting.join().unwrap(), client)
+    }
+
+    #[test]
+    fn clients_sending_the_same_secret_are_matched() {
+        let key: Rsa<Private> = Rsa::generate(2048).unwrap();
+        let user_crush_client: Arc<Mutex<PendingSecrets<MemoryPipe>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let events: Arc<Mutex<VecDeque<Event<MemoryPipe>>>> = Arc::new(Mutex::new(VecDeque::new()));
+        let mut channels: Vec<SecureChannel<MemoryPipe>> = Vec::new();
+        let mut handlers: Vec<thread::JoinHandle<()>> = Vec::new();
+        for _ in 0..2 {
+            let (channel, client) = connect_client(&key);
+            channel
+                .get_ref()
+                .set_read_timeout(Some(Duration::from_millis(100)))
+                .unwrap();
+            let client: Arc<Mutex<Client<MemoryPipe>>> = Arc::new(Mutex::new(client));
+            let cloned_user_crush_client = user_crush_client.clone();
+            let cloned_events = events.clone();
+            handlers.push(thread::spawn(move || {
+                handle_client_messages(
+                    client,
+                    cloned_user_crush_client,
+                    cloned_events,
+                    HeartbeatConfig::default(),
+                )
+            }));
+            channels.push(channel);
+        }
+        for channel in channels.iter_mut() {
+            let secret: Vec<u8> = b"alice\nbob\n".to_vec();
+            channel
+                .send_reliable(Secret { secret }.to_message())
+                .unwrap();
+        }
+        // Both hear about the match, whoever sent their secret first
+        for channel in channels.iter_mut() {
+            let news: Message = loop {
+                match channel.recv() {
+                    Ok(message) if message.message_type == MessageType::DEBUG => break message,
+                    Ok(_) | Err(ProtocolError::Timeout) => {}
+                    Err(err) => panic!("Expected news of the match, got {:?}", err),
+                }
+            };
+            assert_eq!(news.content, b"MATCH OBTAINED");
+        }
+        assert!(user_crush_client.lock().unwrap().is_empty());
+        // Saying goodbye ends each client's thread, which reports the client as gone
+        for channel in channels.iter_mut() {
+            channel.close(GoodbyeReason::Leaving).unwrap();
+        }
+        for handler in handlers {
+            handler.join().unwrap();
+        }
+        let events: MutexGuard<VecDeque<Event<MemoryPipe>>> = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events
+            .iter()
+            .all(|event| matches!(event, Event::ClientDisconnected(_))));
+    }
+}